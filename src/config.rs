@@ -0,0 +1,35 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::options::{HostCredentials, TlsOptions};
+
+/// config file that is read if `--config` is not given
+pub const DEFAULT_CONFIG: &str = "downloader.json";
+
+/// settings of the json config file, the command line adds to them
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub tls: TlsOptions,
+    /// per host credentials, used after the ones of the command line and the environment
+    #[serde(default)]
+    pub credentials: Vec<HostCredentials>,
+}
+
+impl Config {
+    /// reads `path`, or the default config file if it exists
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG).exists() => Path::new(DEFAULT_CONFIG),
+            None => return Ok(Self::default()),
+        };
+
+        let file = std::fs::File::open(path)?;
+        let config = serde_json::from_reader(std::io::BufReader::new(file))?;
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,190 @@
+use std::path::Path;
+
+use cookie_store::{CookieDomain, CookieExpiration, RawCookie};
+use reqwest_cookie_store::CookieStoreMutex;
+use time::OffsetDateTime;
+use url::Url;
+
+use crate::download::part_file;
+
+/// prefix of the lines of http-only cookies, which would otherwise be comments
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+/// adds the cookies of a netscape cookies.txt file to `jar` and returns how many there were,
+/// expired cookies and malformed lines are skipped
+pub fn load(jar: &CookieStoreMutex, path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+
+    let mut store = jar.lock().unwrap();
+    let mut count = 0;
+
+    for (i, line) in content.lines().enumerate() {
+        let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+            Some(line) => (line, true),
+            None => (line, false),
+        };
+
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields = line.split('\t').collect::<Vec<_>>();
+        let [domain, include_subdomains, cookie_path, secure, expires, name, value] = fields[..] else {
+            eprintln!("Skipping malformed line {} of {}", i + 1, path.to_string_lossy());
+            continue;
+        };
+
+        let expires = expires.parse::<i64>().unwrap_or_default();
+        if expires != 0 && expires <= now {
+            continue;
+        }
+
+        let host = domain.trim_start_matches('.');
+        let secure = secure.eq_ignore_ascii_case("TRUE");
+
+        let mut cookie = RawCookie::build((name.to_string(), value.to_string()))
+            .path(cookie_path.to_string())
+            .secure(secure)
+            .http_only(http_only);
+
+        // without the domain attribute the cookie is only sent to exactly this host
+        if include_subdomains.eq_ignore_ascii_case("TRUE") {
+            cookie = cookie.domain(host.to_string());
+        }
+
+        // 0 marks a session cookie
+        if expires != 0 {
+            if let Ok(expires) = OffsetDateTime::from_unix_timestamp(expires) {
+                cookie = cookie.expires(expires);
+            }
+        }
+
+        let url = match Url::parse(&format!("https://{}{}", host, cookie_path)) {
+            Ok(url) => url,
+            Err(err) => {
+                eprintln!("Skipping cookie {} of line {} ({}): {}", name, i + 1, path.to_string_lossy(), err);
+                continue;
+            }
+        };
+
+        match store.insert_raw(&cookie.build(), &url) {
+            Ok(_) => count += 1,
+            Err(err) => eprintln!("Skipping cookie {} of line {} ({}): {}", name, i + 1, path.to_string_lossy(), err),
+        }
+    }
+
+    Ok(count)
+}
+
+/// writes the unexpired cookies of `jar` to `path` in the netscape cookies.txt format
+pub fn save(jar: &CookieStoreMutex, path: &Path, fsync: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let store = jar.lock().unwrap();
+
+    let mut content = String::from("# Netscape HTTP Cookie File\n");
+
+    for cookie in store.iter_unexpired() {
+        let (domain, include_subdomains) = match &cookie.domain {
+            CookieDomain::HostOnly(host) => (host.to_string(), "FALSE"),
+            CookieDomain::Suffix(domain) => (format!(".{}", domain), "TRUE"),
+            CookieDomain::NotPresent | CookieDomain::Empty => continue,
+        };
+
+        let expires = match cookie.expires {
+            CookieExpiration::AtUtc(expires) => expires.unix_timestamp(),
+            CookieExpiration::SessionEnd => 0,
+        };
+
+        content.push_str(&format!("{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            if cookie.http_only().unwrap_or(false) { HTTP_ONLY_PREFIX } else { "" },
+            domain,
+            include_subdomains,
+            String::from(&cookie.path),
+            if cookie.secure().unwrap_or(false) { "TRUE" } else { "FALSE" },
+            expires,
+            cookie.name(),
+            cookie.value()));
+    }
+
+    part_file::write(path, content.as_bytes(), None, fsync)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a cookies.txt file of its own with `content`
+    fn file(name: &str, content: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("downloader-cookies-{}-{}.txt", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// the names of the cookies `jar` sends to `url`
+    fn sent(jar: &CookieStoreMutex, url: &str) -> Vec<String> {
+        let mut names = jar.lock().unwrap().get_request_values(&Url::parse(url).unwrap()).map(|(name, _)| name.to_string()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn load_skips_comments_expired_and_malformed_lines() {
+        let path = file("skip", concat!(
+            "# Netscape HTTP Cookie File\n",
+            "\n",
+            "example.com\tFALSE\t/\tFALSE\t0\tsession\ta\n",
+            "example.com\tFALSE\t/\tFALSE\t4102444800\tlasting\tb\n",
+            "example.com\tFALSE\t/\tFALSE\t1000\texpired\tc\n",
+            "example.com\tFALSE\t/\tFALSE\t0\ttoo few fields\n",
+        ));
+
+        let jar = CookieStoreMutex::default();
+        assert_eq!(load(&jar, &path).unwrap(), 2);
+        assert_eq!(sent(&jar, "https://example.com/"), vec!["lasting", "session"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_keeps_the_domain_path_and_flags() {
+        let path = file("flags", concat!(
+            ".example.com\tTRUE\t/\tFALSE\t0\tshared\ta\n",
+            "example.com\tFALSE\t/\tFALSE\t0\thost\tb\n",
+            "example.com\tFALSE\t/private\tTRUE\t0\tsecure\tc\n",
+            "#HttpOnly_example.com\tFALSE\t/\tFALSE\t0\thidden\td\n",
+        ));
+
+        let jar = CookieStoreMutex::default();
+        assert_eq!(load(&jar, &path).unwrap(), 4);
+
+        // only the subdomain cookie goes to other hosts and the secure one only over https
+        assert_eq!(sent(&jar, "https://cdn.example.com/"), vec!["shared"]);
+        assert_eq!(sent(&jar, "http://example.com/private/a"), vec!["hidden", "host", "shared"]);
+        assert_eq!(sent(&jar, "https://example.com/private/a"), vec!["hidden", "host", "secure", "shared"]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let path = file("round-trip", concat!(
+            ".example.com\tTRUE\t/\tFALSE\t0\tshared\ta\n",
+            "#HttpOnly_example.com\tFALSE\t/videos\tTRUE\t4102444800\thidden\tb\n",
+        ));
+
+        let jar = CookieStoreMutex::default();
+        load(&jar, &path).unwrap();
+        save(&jar, &path, false).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains(".example.com\tTRUE\t/\tFALSE\t0\tshared\ta\n"));
+        assert!(content.contains("#HttpOnly_example.com\tFALSE\t/videos\tTRUE\t4102444800\thidden\tb\n"));
+
+        let reloaded = CookieStoreMutex::default();
+        assert_eq!(load(&reloaded, &path).unwrap(), 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use md5::Md5;
+use reqwest::header::HeaderMap;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::error::checksum_error::ChecksumError;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl Algorithm {
+    fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Md5 => "md5",
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 => 32,
+            Algorithm::Sha1 => 20,
+            Algorithm::Md5 => 16,
+        }
+    }
+
+    /// the algorithm of a `Digest` or `Repr-Digest` header entry
+    fn from_header(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha-256" => Some(Algorithm::Sha256),
+            "sha" => Some(Algorithm::Sha1),
+            "md5" => Some(Algorithm::Md5),
+            _ => None,
+        }
+    }
+
+    pub fn digest(&self, mut reader: impl Read) -> std::io::Result<Vec<u8>> {
+        fn hash<D: Digest + std::io::Write>(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+            let mut hasher = D::new();
+            std::io::copy(reader, &mut hasher)?;
+            Ok(hasher.finalize().to_vec())
+        }
+
+        match self {
+            Algorithm::Sha256 => hash::<Sha256>(&mut reader),
+            Algorithm::Sha1 => hash::<Sha1>(&mut reader),
+            Algorithm::Md5 => hash::<Md5>(&mut reader),
+        }
+    }
+}
+
+/// expected digest of a download, from the command line, a batch entry or the headers of the server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.algorithm.name(), hex::encode(&self.digest))
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    /// `sha256:<hex>`, `sha1:<hex>` or `md5:<hex>`
+    fn from_str(checksum: &str) -> Result<Self, Self::Err> {
+        let Some((algorithm, digest)) = checksum.split_once(':') else {
+            return Err("checksum must look like sha256:<hex>, sha1:<hex> or md5:<hex>".to_string());
+        };
+
+        let algorithm = match algorithm.to_ascii_lowercase().as_str() {
+            "sha256" => Algorithm::Sha256,
+            "sha1" => Algorithm::Sha1,
+            "md5" => Algorithm::Md5,
+            _ => return Err(format!("unsupported checksum algorithm {}, use sha256, sha1 or md5", algorithm)),
+        };
+
+        match hex::decode(digest.trim()) {
+            Ok(digest) if digest.len() == algorithm.len() => Ok(Self { algorithm, digest }),
+            Ok(digest) => Err(format!("{} checksum has {} bytes instead of {}", algorithm.name(), digest.len(), algorithm.len())),
+            Err(err) => Err(format!("invalid checksum {}: {}", digest, err)),
+        }
+    }
+}
+
+impl Checksum {
+    /// the strongest digest the server sent for the body of a response, `Repr-Digest` is preferred
+    /// over `Digest` and `Content-MD5`
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        for name in ["repr-digest", "digest"] {
+            let entries = headers.get_all(name).iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .filter_map(|entry| {
+                    let (algorithm, digest) = entry.split_once('=')?;
+                    let algorithm = Algorithm::from_header(algorithm.trim())?;
+                    // repr-digest wraps the base64 in colons
+                    let digest = STANDARD.decode(digest.trim().trim_matches(':')).ok()?;
+
+                    (digest.len() == algorithm.len()).then_some(Self { algorithm, digest })
+                });
+
+            if let Some(checksum) = entries.min_by_key(|checksum| checksum.algorithm as u8) {
+                return Some(checksum);
+            }
+        }
+
+        let digest = STANDARD.decode(headers.get("content-md5")?.to_str().ok()?.trim()).ok()?;
+        (digest.len() == Algorithm::Md5.len()).then_some(Self { algorithm: Algorithm::Md5, digest })
+    }
+
+    pub fn matches(&self, content: &[u8]) -> bool {
+        self.algorithm.digest(content).is_ok_and(|digest| digest == self.digest)
+    }
+
+    pub fn verify_file(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let digest = self.algorithm.digest(std::io::BufReader::new(std::fs::File::open(path)?))?;
+
+        if digest != self.digest {
+            return Err(Box::new(ChecksumError {
+                output: path.to_path_buf(),
+                expected: self.clone(),
+                actual: Checksum { algorithm: self.algorithm, digest },
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    const SHA256_HELLO: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    fn header_map(entries: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in entries {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn from_str_parses_and_displays() {
+        let checksum: Checksum = format!("SHA256:{}", SHA256_HELLO).parse().unwrap();
+
+        assert_eq!(checksum.algorithm, Algorithm::Sha256);
+        assert_eq!(checksum.to_string(), format!("sha256:{}", SHA256_HELLO));
+        assert!(checksum.matches(b"hello"));
+        assert!(!checksum.matches(b"hello!"));
+
+        let checksum: Checksum = "md5:5d41402abc4b2a76b9719d911017c592".parse().unwrap();
+        assert!(checksum.matches(b"hello"));
+    }
+
+    #[test]
+    fn from_str_rejects_invalid() {
+        assert!(SHA256_HELLO.parse::<Checksum>().is_err());
+        assert!(format!("sha512:{}", SHA256_HELLO).parse::<Checksum>().is_err());
+        assert!("sha1:aaf4".parse::<Checksum>().is_err());
+        assert!("md5:not hex".parse::<Checksum>().is_err());
+    }
+
+    #[test]
+    fn from_headers_prefers_repr_digest_and_the_strongest() {
+        let headers = header_map(&[
+            ("content-md5", "XUFAKrxLKna5cZ2REBfFkg=="),
+            ("digest", "md5=XUFAKrxLKna5cZ2REBfFkg=="),
+            ("repr-digest", "sha=:qvTGHdzF6KLavt4PO0gs2a6pQ00=:, sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:"),
+        ]);
+
+        let checksum = Checksum::from_headers(&headers).unwrap();
+        assert_eq!(checksum.to_string(), format!("sha256:{}", SHA256_HELLO));
+    }
+
+    #[test]
+    fn from_headers_falls_back() {
+        let headers = header_map(&[
+            ("repr-digest", "sha-512=:AAAA:, sha-256=:dG9vIHNob3J0:"),
+            ("content-md5", "XUFAKrxLKna5cZ2REBfFkg=="),
+        ]);
+        let checksum = Checksum::from_headers(&headers).unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Md5);
+        assert!(checksum.matches(b"hello"));
+
+        let headers = header_map(&[("digest", "SHA=qvTGHdzF6KLavt4PO0gs2a6pQ00=")]);
+        assert_eq!(Checksum::from_headers(&headers).unwrap().algorithm, Algorithm::Sha1);
+
+        assert_eq!(Checksum::from_headers(&HeaderMap::new()), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::options::Options;
+
+/// how long the throughput is measured before the number of parallel requests is changed
+const WINDOW: Duration = Duration::from_secs(2);
+/// the throughput has to improve by this factor for another parallel request
+const IMPROVEMENT: f64 = 1.05;
+/// parallel requests an adaptive download starts with
+const START: usize = 2;
+
+struct State {
+    limit: usize,
+    /// permits that are not given back when their request finishes, after the limit was lowered
+    held_back: usize,
+    window_start: Instant,
+    window_bytes: u64,
+    /// bytes per second of the last window
+    throughput: f64,
+}
+
+/// the permit of a running request, it is given back when it is dropped, so a request that returns
+/// early does not take it along
+pub struct Permit<'a> {
+    concurrency: &'a Concurrency,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Permit<'_> {
+    fn into_inner(mut self) -> OwnedSemaphorePermit {
+        self.permit.take().unwrap()
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.concurrency.release(&mut self.concurrency.state.lock().unwrap(), permit);
+        }
+    }
+}
+
+/// the parallel requests of a single download, a fixed number or adaptive: one more while the
+/// throughput improves and half as many when the server throttles or fails
+pub struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    adaptive: bool,
+    max: usize,
+    state: Mutex<State>,
+}
+
+impl Concurrency {
+    pub fn new(options: &Options) -> Self {
+        let max = options.max_parallel_downloads.max(1);
+        let limit = match options.adaptive {
+            true => START.min(max),
+            false => max,
+        };
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            adaptive: options.adaptive,
+            max,
+            state: Mutex::new(State { limit, held_back: 0, window_start: Instant::now(), window_bytes: 0, throughput: 0. }),
+        }
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        Permit { concurrency: self, permit: Some(Arc::clone(&self.semaphore).acquire_owned().await.unwrap()) }
+    }
+
+    /// the number of parallel requests for the progress output, only if it is adaptive
+    pub fn current(&self) -> Option<usize> {
+        self.adaptive.then(|| self.state.lock().unwrap().limit)
+    }
+
+    /// gives back the permit of a request that downloaded `bytes`
+    pub fn finished(&self, permit: Permit, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        self.release(&mut state, permit.into_inner());
+
+        if !self.adaptive {
+            return;
+        }
+
+        state.window_bytes += bytes;
+
+        let elapsed = state.window_start.elapsed();
+        if elapsed < WINDOW {
+            return;
+        }
+
+        let throughput = state.window_bytes as f64 / elapsed.as_secs_f64();
+        if throughput > state.throughput * IMPROVEMENT && state.limit < self.max {
+            state.limit += 1;
+
+            match state.held_back {
+                0 => self.semaphore.add_permits(1),
+                _ => state.held_back -= 1,
+            }
+        }
+
+        state.throughput = throughput;
+        state.window_start = Instant::now();
+        state.window_bytes = 0;
+    }
+
+    /// gives back the permit of a failed request, the limit is halved if the server was overloaded
+    pub fn failed(&self, permit: Permit, err: &(dyn std::error::Error + Send + 'static)) {
+        let mut state = self.state.lock().unwrap();
+
+        if self.adaptive && is_throttled(err) && state.limit > 1 {
+            let limit = (state.limit / 2).max(1);
+            state.held_back += state.limit - limit;
+            state.limit = limit;
+
+            // the throughput of the lowered limit is measured from scratch
+            state.window_start = Instant::now();
+            state.window_bytes = 0;
+            state.throughput = 0.;
+
+            info!("Server is throttling, lowering to {} parallel requests", limit);
+        }
+
+        self.release(&mut state, permit.into_inner());
+    }
+
+    fn release(&self, state: &mut State, permit: OwnedSemaphorePermit) {
+        match state.held_back {
+            0 => drop(permit),
+            _ => {
+                state.held_back -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// whether `err` is a 429, a server error or a timeout, which more parallel requests make worse
+pub fn is_throttled(err: &(dyn std::error::Error + Send + 'static)) -> bool {
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(err) => err.is_timeout() || err.status().is_some_and(|status| status.as_u16() == 429 || status.is_server_error()),
+        None => false,
+    }
+}
+
+/// the concurrency for the progress output, empty if it is not adaptive
+pub fn format_concurrency(concurrency: Option<usize>) -> String {
+    match concurrency {
+        Some(concurrency) => format!("\t {} parallel", concurrency),
+        None => String::new(),
+    }
+}
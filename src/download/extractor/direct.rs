@@ -0,0 +1,23 @@
+use async_trait::async_trait;
+use url::Url;
+
+use crate::download::{page, DownloadClient};
+use super::{Extractor, MediaDescriptor};
+
+/// urls that already point to a video or playlist
+pub struct DirectExtractor;
+
+#[async_trait(?Send)]
+impl Extractor for DirectExtractor {
+    fn name(&self) -> &'static str {
+        "url"
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        page::is_media(url)
+    }
+
+    async fn extract(&self, _client: &DownloadClient, url: &Url) -> Result<Vec<MediaDescriptor>, Box<dyn std::error::Error>> {
+        Ok(vec![MediaDescriptor::new(url.clone())])
+    }
+}
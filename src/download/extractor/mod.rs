@@ -0,0 +1,63 @@
+pub mod direct;
+
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+use url::Url;
+
+use crate::download::DownloadClient;
+
+/// an alternative of a media file, e.g. another quality
+#[derive(Debug, Clone)]
+pub struct Variant {
+    pub url: Url,
+    pub height: Option<u32>,
+}
+
+/// a media file found by an extractor
+#[derive(Debug, Clone)]
+pub struct MediaDescriptor {
+    pub url: Url,
+    /// headers the media requests need, e.g. `Referer`
+    pub headers: HeaderMap,
+    pub title: Option<String>,
+    pub height: Option<u32>,
+    pub variants: Vec<Variant>,
+}
+
+impl MediaDescriptor {
+    pub fn new(url: Url) -> Self {
+        Self { url, headers: HeaderMap::new(), title: None, height: None, variants: Vec::new() }
+    }
+}
+
+/// finds the media of the pages of a specific site (or kind of url),
+/// the generic page search is only used if no extractor matches the url
+#[async_trait(?Send)]
+pub trait Extractor: Send + Sync {
+    /// shown as the source of the media it finds
+    fn name(&self) -> &'static str;
+
+    fn matches(&self, url: &Url) -> bool;
+
+    async fn extract(&self, client: &DownloadClient, url: &Url) -> Result<Vec<MediaDescriptor>, Box<dyn std::error::Error>>;
+}
+
+static REGISTRY: RwLock<Vec<Arc<dyn Extractor>>> = RwLock::new(Vec::new());
+
+/// adds an extractor, it is consulted before all extractors that were registered earlier
+pub fn register(extractor: Arc<dyn Extractor>) {
+    REGISTRY.write().unwrap().insert(0, extractor);
+}
+
+/// registers the extractors that come with the downloader, they are consulted after the ones
+/// that were registered before
+pub fn register_defaults() {
+    REGISTRY.write().unwrap().push(Arc::new(direct::DirectExtractor));
+}
+
+/// the extractor responsible for `url`, if any
+pub fn find(url: &Url) -> Option<Arc<dyn Extractor>> {
+    REGISTRY.read().unwrap().iter().find(|extractor| extractor.matches(url)).cloned()
+}
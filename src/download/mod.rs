@@ -1,34 +1,194 @@
 pub mod search;
+pub mod checksum;
+pub mod concurrency;
+pub mod tls;
+pub mod extractor;
+pub mod output;
+pub mod page;
+pub mod part_file;
 pub mod playlist;
+pub mod rate;
+pub mod scheduler;
+pub mod verify;
 pub mod video;
 
 
-use bytes::Bytes;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use reqwest::header::{HeaderMap, CONTENT_LENGTH};
 use url::Url;
 
+use crate::options::{Auth, HostCredentials, HttpVersion, Options};
+use rate::RateLimiter;
+use checksum::Checksum;
+use tls::Tls;
+
+/// what was downloaded for a requested url
+#[derive(Debug, Clone)]
+pub struct Downloaded {
+    /// the file the download was written to
+    pub output: PathBuf,
+    /// the files a playlist split at its discontinuities was written to instead of `output`
+    pub parts: Vec<PathBuf>,
+    /// the video or playlist the output was downloaded from
+    pub media_url: Url,
+    /// the stream that was selected from a master playlist
+    pub variant: Option<String>,
+    /// checksum of the whole file the server sent
+    pub checksum: Option<Checksum>,
+}
+
+impl Downloaded {
+    /// the files that were written, `output` or the parts of a split playlist
+    pub fn outputs(&self) -> Vec<&Path> {
+        match self.parts.is_empty() {
+            true => vec![self.output.as_path()],
+            false => self.parts.iter().map(PathBuf::as_path).collect(),
+        }
+    }
+}
+
+/// the proxy `url` points to, with the credentials it contains, the hosts in `NO_PROXY` are not proxied
+pub fn proxy(url: &str) -> Result<reqwest::Proxy, Box<dyn std::error::Error>> {
+    let parsed = Url::parse(url)?;
+
+    if !matches!(parsed.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!("Unsupported proxy scheme {}, use http, https or socks5", parsed.scheme()).into());
+    }
+
+    Ok(reqwest::Proxy::all(url)?.no_proxy(reqwest::NoProxy::from_env()))
+}
+
+/// whether the host of `url` is `pattern`, `*.example.com` also matches the subdomains
+pub fn host_matches(pattern: &str, url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host == domain || host.ends_with(&format!(".{}", domain)),
+        None => host == pattern,
+    }
+}
+
+/// the content length of response `headers`
+pub fn content_length(headers: &HeaderMap) -> Result<u64, Box<dyn std::error::Error>> {
+    match headers.get(CONTENT_LENGTH) {
+        Some(header_value) => Ok(header_value.to_str().unwrap_or_default().parse::<u64>()?),
+        None => {
+            error!("Response does not have the content length even if it is a video response");
+            Err("Need content length in header to download video file".into())
+        }
+    }
+}
+
+/// http client shared by every request of a run, so connections are reused between the page,
+/// playlist and segment requests, cloning it keeps using the same connections
+#[derive(Clone)]
 pub struct DownloadClient {
     client: reqwest::Client,
+    /// client that does not verify certificates, used for the insecure hosts of the options
+    insecure_client: Option<reqwest::Client>,
+    insecure_hosts: Vec<String>,
+    credentials: Vec<HostCredentials>,
+    /// sent with every request, the headers of the options and of `with_headers`
+    headers: HeaderMap,
+    rate_limiter: Arc<RateLimiter>,
 }
 
 
 impl DownloadClient {
-    pub fn new() -> Self {
-        let client = reqwest::Client::builder()
-            .user_agent("Mozilla/5.0 (Windows NT 10.0; rv:78.0) Gecko/20100101 Firefox/78.0")
-            .build()
-            .unwrap();
+    /// a client with the user agent, headers, cookie jar, proxy, tls and connection settings of `options`
+    pub fn new(options: &Options) -> Result<Self, Box<dyn std::error::Error>> {
+        let tls = Tls::load(&options.tls)?;
+        let proxy = options.proxy.as_deref().map(proxy).transpose()?;
+
+        let build = |accept_invalid_certs: bool| {
+            let mut builder = reqwest::Client::builder()
+                .user_agent(options.user_agent.as_str())
+                .cookie_provider(Arc::clone(&options.cookie_jar))
+                .danger_accept_invalid_certs(accept_invalid_certs)
+                .pool_max_idle_per_host(options.pool_size)
+                .connect_timeout(Duration::from_secs(options.connect_timeout))
+                .read_timeout(Duration::from_secs(options.read_timeout));
+
+            builder = match options.keep_alive {
+                // idle connections are closed right away, every request opens a new one
+                0 => builder.pool_max_idle_per_host(0),
+                keep_alive => builder
+                    .pool_idle_timeout(Duration::from_secs(keep_alive))
+                    .tcp_keepalive(Duration::from_secs(keep_alive)),
+            };
+
+            builder = match options.http_version {
+                HttpVersion::Auto => builder,
+                HttpVersion::Http1 => builder.http1_only(),
+                HttpVersion::Http2 => builder.http2_prior_knowledge(),
+            };
+
+            if let Some(proxy) = proxy.clone() {
+                builder = builder.proxy(proxy);
+            }
 
-        Self { client }
+            tls.apply(builder).build()
+        };
+
+        Ok(Self {
+            client: build(false)?,
+            insecure_client: match options.tls.insecure_hosts.is_empty() {
+                true => None,
+                false => Some(build(true)?),
+            },
+            insecure_hosts: options.tls.insecure_hosts.clone(),
+            credentials: options.credentials.clone(),
+            headers: options.headers.clone(),
+            rate_limiter: Arc::clone(&options.rate_limiter),
+        })
+    }
+
+    /// a client using the same connections that also sends `headers`, the headers of the
+    /// options take precedence
+    pub fn with_headers(&self, headers: &HeaderMap) -> Self {
+        let mut client = self.clone();
+        client.headers = headers.clone();
+        client.headers.extend(self.headers.clone());
+
+        client
     }
 
-    async fn head(&self, url: &Url) -> Result<HeaderMap, Box<dyn std::error::Error>> {
-        let request = self.client.head(url.as_str());
+    /// `request` with the credentials of the host of `url`, if there are any
+    fn authorize(&self, request: RequestBuilder, url: &Url) -> RequestBuilder {
+        match self.credentials.iter().find(|credentials| host_matches(&credentials.host, url)).map(|credentials| &credentials.auth) {
+            Some(Auth::Basic { username, password }) => request.basic_auth(username, Some(password)),
+            Some(Auth::Bearer { token }) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    fn client_for(&self, url: &Url) -> &reqwest::Client {
+        match &self.insecure_client {
+            Some(insecure_client) if tls::is_insecure(&self.insecure_hosts, url) => insecure_client,
+            _ => &self.client,
+        }
+    }
+
+    pub async fn head(&self, url: &Url) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+        self.head_header(url, &HeaderMap::new()).await
+    }
+
+    async fn head_header(&self, url: &Url, headers: &HeaderMap) -> Result<HeaderMap, Box<dyn std::error::Error>> {
+        let request = self.authorize(self.client_for(url).head(url.as_str()), url).headers(headers.to_owned())
+            // the headers of the options come last, so a --referer or -H beats the ones of the call
+            .headers(self.headers.clone());
 
         let response = match request.send().await {
             Ok(response) => response,
             Err(err) => {
-                eprintln!("Error downloading {}: {}", url, err);
+                error!("Error downloading {}: {}", url, err);
                 return Err(Box::new(err));
             }
         };
@@ -36,7 +196,7 @@ impl DownloadClient {
         match response.error_for_status_ref() {
             Ok(_) => {}
             Err(err) => {
-                eprintln!("Error downloading {}: {}", url, err);
+                error!("Error downloading {}: {}", url, err);
                 return Err(Box::new(err));
             }
         }
@@ -44,30 +204,55 @@ impl DownloadClient {
         Ok(response.headers().clone())
     }
 
-    pub async fn get_content_length(&self, url: &Url) -> Result<u64, Box<dyn std::error::Error>> {
-        let headers = self.head(url).await?;
-        let content_length = match headers.get(CONTENT_LENGTH) {
-            Some(header_value) => header_value.to_str().unwrap_or_default().parse::<u64>()?,
-            None => {
-                eprintln!("Response does not have the content length even if it is a video response");
-                return Err("Need content length in header to download video file".into());
-            }
-        };
-
-        Ok(content_length)
-    }
-
     pub async fn download(&self, url: &Url) -> Result<Bytes, Box<dyn std::error::Error + Send>> {
         self.download_header(url, &HeaderMap::new()).await
     }
 
     pub async fn download_header(&self, url: &Url, headers: &HeaderMap) -> Result<Bytes, Box<dyn std::error::Error + Send>> {
-        let request = self.client.get(url.as_str()).headers(headers.to_owned());
+        let (_, bytes) = self.download_response(url, headers).await?;
+        Ok(bytes)
+    }
+
+    /// the headers and the complete body of a get request
+    pub async fn download_response(&self, url: &Url, headers: &HeaderMap) -> Result<(HeaderMap, Bytes), Box<dyn std::error::Error + Send>> {
+        let mut response = self.get_header(url, headers).await?;
+        let response_headers = response.headers().clone();
+
+        // a digest of a partial response would only cover the range
+        let checksum = match response.status() {
+            StatusCode::OK => Checksum::from_headers(response.headers()),
+            _ => None,
+        };
+        let content_length = response.content_length();
+        let mut bytes = BytesMut::with_capacity(content_length.unwrap_or_default() as usize);
+
+        while let Some(chunk) = self.chunk(url, &mut response).await? {
+            bytes.extend_from_slice(&chunk);
+        }
+
+        if let Some(content_length) = content_length {
+            if bytes.len() as u64 != content_length {
+                error!("Error reading response: got {} of {} bytes from {}", bytes.len(), content_length, url);
+                return Err(Box::new(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Incomplete response body")));
+            }
+        }
+
+        if let Some(checksum) = checksum.filter(|checksum| !checksum.matches(&bytes)) {
+            error!("Error reading response: {} does not match the {} checksum of the server", url, checksum);
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, "Response does not match its checksum")));
+        }
+
+        Ok((response_headers, bytes.freeze()))
+    }
+
+    /// sends a get request and checks its status, the body is read with `chunk`
+    pub async fn get_header(&self, url: &Url, headers: &HeaderMap) -> Result<Response, Box<dyn std::error::Error + Send>> {
+        let request = self.authorize(self.client_for(url).get(url.as_str()), url).headers(headers.to_owned()).headers(self.headers.clone());
 
         let response = match request.send().await {
             Ok(response) => response,
             Err(err) => {
-                eprintln!("Error downloading {}: {}", url, err);
+                error!("Error downloading {}: {}", url, err);
                 return Err(Box::new(err));
             }
         };
@@ -75,19 +260,27 @@ impl DownloadClient {
         match response.error_for_status_ref() {
             Ok(_) => {}
             Err(err) => {
-                eprintln!("Error downloading {}: {}", url, err);
+                error!("Error downloading {}: {}", url, err);
                 return Err(Box::new(err));
             }
         }
 
-        let bytes = match response.bytes().await {
-            Ok(bytes) => bytes,
+        Ok(response)
+    }
+
+    /// the next chunk of the body of `response` from `url`, read chunk by chunk so the rate limits
+    /// hold back the parallel downloads while they run
+    pub async fn chunk(&self, url: &Url, response: &mut Response) -> Result<Option<Bytes>, Box<dyn std::error::Error + Send>> {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                self.rate_limiter.acquire(url, chunk.len()).await;
+                Ok(Some(chunk))
+            }
+            Ok(None) => Ok(None),
             Err(err) => {
-                eprintln!("Error reading response: {}", err);
-                return Err(Box::new(err));
+                error!("Error reading response: {}", err);
+                Err(Box::new(err))
             }
-        };
-
-        Ok(bytes)
+        }
     }
 }
@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use url::Url;
+
+/// longest value a placeholder is replaced with, most file systems allow 255 bytes per name
+const MAX_VALUE_LEN: usize = 150;
+
+/// what is known about a download before it starts, the values of the template placeholders
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    /// host of the requested page without `www.`
+    pub site: String,
+    /// height of the selected playlist variant or the candidate
+    pub variant_height: Option<u32>,
+    pub ext: String,
+}
+
+impl Metadata {
+    pub fn new(page_url: &Url, media_url: &Url, title: Option<String>, variant_height: Option<u32>) -> Self {
+        let host = page_url.host_str().unwrap_or_default();
+
+        // without a title the media file name is the best guess
+        let title = title.filter(|title| !title.trim().is_empty()).or_else(|| {
+            let file_name = media_url.path().rsplit('/').next().unwrap_or_default();
+            let stem = file_name.rsplit_once('.').map_or(file_name, |(stem, _)| stem);
+            Some(stem.to_string()).filter(|stem| !stem.is_empty())
+        });
+
+        Self {
+            title,
+            site: host.strip_prefix("www.").unwrap_or(host).to_string(),
+            variant_height,
+            // every download is converted to mp4 by ffmpeg at the end
+            ext: "mp4".to_string(),
+        }
+    }
+}
+
+/// replaces the placeholders `{title}`, `{site}`, `{date}`, `{variant_height}` and `{ext}` of
+/// `template` and places the result in `directory`, unknown placeholders are kept as they are
+pub fn render(template: &str, directory: &Path, metadata: &Metadata) -> PathBuf {
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else { break };
+        let name = &rest[1..end];
+
+        let value = match name {
+            "title" => Some(metadata.title.clone().unwrap_or_else(|| "video".to_string())),
+            "site" => Some(metadata.site.clone()),
+            "date" => Some(today()),
+            "variant_height" => Some(metadata.variant_height.map_or("unknown".to_string(), |height| height.to_string())),
+            "ext" => Some(metadata.ext.clone()),
+            _ => None,
+        };
+
+        match value {
+            Some(value) => rendered.push_str(&sanitize(&value)),
+            None => rendered.push_str(&rest[..=end]),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    rendered.push_str(rest);
+
+    directory.join(rendered)
+}
+
+/// makes `value` usable as (part of) a file name on every common file system: path separators,
+/// reserved and control characters are replaced, whitespace is collapsed and the length is limited
+pub fn sanitize(value: &str) -> String {
+    let replaced = value.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => ' ',
+            c => c,
+        })
+        .collect::<String>();
+
+    let mut sanitized = replaced.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if sanitized.len() > MAX_VALUE_LEN {
+        let mut end = MAX_VALUE_LEN;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+    }
+
+    // leading dots hide files and trailing dots and spaces are dropped by windows
+    let sanitized = sanitized.trim_start_matches('.').trim_end_matches(['.', ' ']).trim_start();
+
+    if sanitized.is_empty() {
+        "_".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
+/// the current utc date as `YYYY-MM-DD`
+fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs() / 86400).unwrap_or_default() as i64;
+
+    // converts days since 1970-01-01 to a civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> Metadata {
+        Metadata { title: Some("My: Video?".to_string()), site: "example.com".to_string(), variant_height: Some(720), ext: "mp4".to_string() }
+    }
+
+    #[test]
+    fn render_replaces_the_placeholders() {
+        let rendered = render("{site}/{title} [{variant_height}p].{ext}", Path::new("out"), &metadata());
+
+        // a separator of the template makes a folder, the one of a value does not
+        assert_eq!(rendered, Path::new("out/example.com/My_ Video_ [720p].mp4"));
+    }
+
+    #[test]
+    fn render_falls_back_for_missing_values() {
+        let rendered = render("{title}-{variant_height}.{ext}", Path::new(""), &Metadata { ext: "mp4".to_string(), ..Default::default() });
+
+        assert_eq!(rendered, Path::new("video-unknown.mp4"));
+    }
+
+    #[test]
+    fn render_keeps_unknown_and_unclosed_placeholders() {
+        assert_eq!(render("{uploader} {title}", Path::new(""), &metadata()), Path::new("{uploader} My_ Video_"));
+        assert_eq!(render("{title} {ext", Path::new(""), &metadata()), Path::new("My_ Video_ {ext"));
+    }
+
+    #[test]
+    fn render_date() {
+        let rendered = render("{date}", Path::new(""), &metadata()).to_string_lossy().to_string();
+
+        let parts = rendered.split('-').map(|part| part.len()).collect::<Vec<_>>();
+        assert_eq!(parts, vec![4, 2, 2]);
+        assert!(rendered.chars().all(|c| c.is_ascii_digit() || c == '-'));
+    }
+
+    #[test]
+    fn sanitize_replaces_separators_and_reserved_characters() {
+        assert_eq!(sanitize("a/b\\c:d*e?f\"g<h>i|j"), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize("../../etc/passwd"), "_.._etc_passwd");
+    }
+
+    #[test]
+    fn sanitize_collapses_whitespace_and_control_characters() {
+        assert_eq!(sanitize("  a \t b\n\u{7}c  "), "a b c");
+    }
+
+    #[test]
+    fn sanitize_trims_dots_and_empty_values() {
+        assert_eq!(sanitize(".hidden. "), "hidden");
+        assert_eq!(sanitize(".."), "_");
+        assert_eq!(sanitize(" \n "), "_");
+    }
+
+    #[test]
+    fn sanitize_limits_the_length_at_a_char_boundary() {
+        assert_eq!(sanitize(&"a".repeat(200)).len(), MAX_VALUE_LEN);
+
+        // 'é' takes two bytes, the cut falls in the middle of one
+        let sanitized = sanitize(&format!("a{}", "é".repeat(100)));
+        assert_eq!(sanitized.len(), MAX_VALUE_LEN - 1);
+        assert!(sanitized.ends_with('é'));
+    }
+}
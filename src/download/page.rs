@@ -0,0 +1,389 @@
+use std::cmp::Reverse;
+
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, REFERER};
+use scraper::{Html, Selector};
+use url::Url;
+
+use crate::download::DownloadClient;
+use crate::download::extractor::MediaDescriptor;
+
+/// extensions of the media files that are searched for, the supported ones first
+const MEDIA_EXTENSIONS: [&str; 4] = ["m3u8", "mp4", "webm", "mpd"];
+
+/// a media url found in a page or by an extractor
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub url: Url,
+    /// where in the page the url was found, e.g. `<source src>` or `<script>`, or the extractor name
+    pub source: &'static str,
+    /// headers the media requests need
+    pub headers: HeaderMap,
+    pub title: Option<String>,
+    pub height: Option<u32>,
+    /// content length reported by the HEAD request, if it was probed
+    pub size: Option<u64>,
+    pub content_type: Option<String>,
+    /// whether the HEAD request failed
+    pub unreachable: bool,
+    /// the pages that were followed to find the url, the last one contains it
+    pub pages: Vec<Url>,
+}
+
+impl Candidate {
+    pub fn new(url: Url, source: &'static str) -> Self {
+        Self {
+            url,
+            source,
+            headers: HeaderMap::new(),
+            title: None,
+            height: None,
+            size: None,
+            content_type: None,
+            unreachable: false,
+            pages: Vec::new(),
+        }
+    }
+
+    /// the descriptor and each of its variants as a candidate
+    pub fn from_descriptor(descriptor: MediaDescriptor, source: &'static str) -> Vec<Self> {
+        let mut candidate = Candidate::new(descriptor.url, source);
+        candidate.headers = descriptor.headers;
+        candidate.title = descriptor.title;
+        candidate.height = descriptor.height;
+
+        let mut candidates = vec![candidate.clone()];
+
+        for variant in descriptor.variants {
+            if candidates.iter().all(|existing| existing.url != variant.url) {
+                candidates.push(Candidate { url: variant.url, height: variant.height, ..candidate.clone() });
+            }
+        }
+
+        candidates
+    }
+
+    /// media found in a page is requested with the page as referer, like a browser does
+    fn set_referer(&mut self, page: &Url) {
+        if let Ok(referer) = HeaderValue::from_str(page.as_str()) {
+            self.headers.insert(REFERER, referer);
+        }
+    }
+
+    /// index of the extension in `MEDIA_EXTENSIONS`, unknown extensions rank last
+    fn support_rank(&self) -> usize {
+        let extension = extension(&self.url);
+        MEDIA_EXTENSIONS.iter().position(|known| *known == extension).unwrap_or(MEDIA_EXTENSIONS.len())
+    }
+
+    /// unreachable urls and html pages are most likely not the video
+    fn is_usable(&self) -> bool {
+        !self.unreachable && !self.content_type.as_deref().is_some_and(|content_type| content_type.starts_with("text/html"))
+    }
+}
+
+/// whether the url points to a media file (or manifest) instead of a page
+pub fn is_media(url: &Url) -> bool {
+    MEDIA_EXTENSIONS.contains(&extension(url).as_str())
+}
+
+/// the lowercase file extension of the url path, empty if it has none
+pub fn extension(url: &Url) -> String {
+    let file_name = url.path().rsplit('/').next().unwrap_or_default();
+
+    match file_name.rsplit_once('.') {
+        Some((_, extension)) => extension.to_lowercase(),
+        None => String::new(),
+    }
+}
+
+/// undoes the escaping of urls embedded in javascript and json strings (`https:\/\/`, `\u002F`)
+fn unescape_script(script: &str) -> String {
+    script
+        .replace("\\u002F", "/")
+        .replace("\\u002f", "/")
+        .replace("\\u0026", "&")
+        .replace("\\/", "/")
+        .replace("\\\"", "\"")
+        .replace("\\'", "'")
+}
+
+/// all urls of `@type: VideoObject` entries in a json-ld document
+fn json_ld_urls(value: &serde_json::Value, urls: &mut Vec<(String, &'static str)>) {
+    match value {
+        serde_json::Value::Object(object) => {
+            let is_video = match object.get("@type") {
+                Some(serde_json::Value::String(kind)) => kind == "VideoObject",
+                Some(serde_json::Value::Array(kinds)) => kinds.iter().any(|kind| kind == "VideoObject"),
+                _ => false,
+            };
+
+            if is_video {
+                if let Some(serde_json::Value::String(url)) = object.get("contentUrl") {
+                    urls.push((url.to_string(), "json-ld VideoObject"));
+                }
+            }
+
+            object.values().for_each(|value| json_ld_urls(value, urls));
+        }
+        serde_json::Value::Array(values) => values.iter().for_each(|value| json_ld_urls(value, urls)),
+        _ => {}
+    }
+}
+
+/// the `name` of the first `@type: VideoObject` entry in a json-ld document
+fn json_ld_name(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Object(object) => {
+            let is_video = match object.get("@type") {
+                Some(serde_json::Value::String(kind)) => kind == "VideoObject",
+                Some(serde_json::Value::Array(kinds)) => kinds.iter().any(|kind| kind == "VideoObject"),
+                _ => false,
+            };
+
+            match object.get("name") {
+                Some(serde_json::Value::String(name)) if is_video => Some(name.to_string()),
+                _ => object.values().find_map(json_ld_name),
+            }
+        }
+        serde_json::Value::Array(values) => values.iter().find_map(json_ld_name),
+        _ => None,
+    }
+}
+
+/// the title of the page, taken from `og:title`, the json-ld `VideoObject` or `<title>` in that order
+pub fn find_title(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+
+    let og_title = Selector::parse("meta[property='og:title'], meta[name='twitter:title']").unwrap();
+    let json_ld = Selector::parse("script[type='application/ld+json']").unwrap();
+    let title = Selector::parse("title").unwrap();
+
+    document.select(&og_title)
+        .find_map(|element| element.value().attr("content").map(str::to_string))
+        .or_else(|| document.select(&json_ld).find_map(|element| {
+            let value = serde_json::from_str::<serde_json::Value>(&element.text().collect::<String>()).ok()?;
+            json_ld_name(&value)
+        }))
+        .or_else(|| document.select(&title).next().map(|element| element.text().collect::<String>()))
+        .map(|title| title.trim().to_string())
+        .filter(|title| !title.is_empty())
+}
+
+/// finds the media urls of a page in the `<video>`/`<source>` elements, `og:video` meta tags,
+/// json-ld `VideoObject`s and urls embedded in scripts, resolved against `page_url`
+pub fn find_media(html: &str, page_url: &Url) -> Vec<Candidate> {
+    let document = Html::parse_document(html);
+    let mut found: Vec<(String, &'static str)> = Vec::new();
+
+    let elements = Selector::parse("video[src], video[data-src], source[src], source[data-src]").unwrap();
+    for element in document.select(&elements) {
+        let value = element.value();
+        let source = match (value.name(), value.attr("src").is_some()) {
+            ("video", true) => "<video src>",
+            ("video", false) => "<video data-src>",
+            (_, true) => "<source src>",
+            (_, false) => "<source data-src>",
+        };
+        found.extend(value.attr("src").or(value.attr("data-src")).map(|url| (url.to_string(), source)));
+    }
+
+    let meta = Selector::parse(concat!(
+        "meta[property='og:video'], meta[property='og:video:url'], meta[property='og:video:secure_url'], ",
+        "meta[name='twitter:player:stream']",
+    )).unwrap();
+    for element in document.select(&meta) {
+        found.extend(element.value().attr("content").map(|url| (url.to_string(), "<meta og:video>")));
+    }
+
+    let json_ld = Selector::parse("script[type='application/ld+json']").unwrap();
+    for element in document.select(&json_ld) {
+        let text = element.text().collect::<String>();
+        if let Ok(value) = serde_json::from_str::<serde_json::Value>(&text) {
+            json_ld_urls(&value, &mut found);
+        }
+    }
+
+    // only quoted strings ending in a media extension are taken from scripts, everything else is code
+    let quoted = Regex::new(r#"["']([^"'\s<>]+?\.(?:m3u8|mp4|webm|mpd)(?:\?[^"'\s<>]*)?)["']"#).unwrap();
+    let scripts = Selector::parse("script:not([type='application/ld+json'])").unwrap();
+    for element in document.select(&scripts) {
+        let text = unescape_script(&element.text().collect::<String>());
+        found.extend(quoted.captures_iter(&text).map(|captures| (captures[1].to_string(), "<script>")));
+    }
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for (url, source) in found {
+        let url = match page_url.join(url.trim()) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+
+        if matches!(url.scheme(), "http" | "https") && !candidates.iter().any(|candidate| candidate.url == url) {
+            let mut candidate = Candidate::new(url, source);
+            candidate.set_referer(page_url);
+            candidates.push(candidate);
+        }
+    }
+
+    candidates
+}
+
+/// the sources of the `<iframe>` and `<embed>` elements of a page, resolved against `page_url`
+pub fn find_frames(html: &str, page_url: &Url) -> Vec<Url> {
+    let document = Html::parse_document(html);
+    let frames = Selector::parse("iframe[src], iframe[data-src], embed[src]").unwrap();
+
+    let mut urls: Vec<Url> = Vec::new();
+
+    for element in document.select(&frames) {
+        let value = element.value();
+        let url = match value.attr("src").or(value.attr("data-src")).map(|src| page_url.join(src.trim())) {
+            Some(Ok(url)) => url,
+            _ => continue,
+        };
+
+        if matches!(url.scheme(), "http" | "https") && !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+
+    urls
+}
+
+/// fills in size and content type of every candidate with a HEAD request
+pub async fn probe(client: &DownloadClient, candidates: &mut [Candidate]) {
+    for candidate in candidates.iter_mut() {
+        match client.head_header(&candidate.url, &candidate.headers).await {
+            Ok(headers) => {
+                candidate.size = headers.get(CONTENT_LENGTH)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse().ok());
+                candidate.content_type = headers.get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string);
+            }
+            Err(_) => candidate.unreachable = true,
+        }
+    }
+}
+
+/// orders the candidates from best to worst: reachable media first, then by how well the
+/// format is supported, then higher resolutions and larger files first, otherwise in page order
+pub fn rank(candidates: &mut [Candidate]) {
+    candidates.sort_by_key(|candidate| (
+        !candidate.is_usable(),
+        candidate.support_rank(),
+        Reverse(candidate.height.unwrap_or_default()),
+        Reverse(candidate.size.unwrap_or_default()),
+    ));
+}
+
+pub fn print_candidates(candidates: &[Candidate]) {
+    let source_width = candidates.iter().map(|candidate| candidate.source.len()).chain(std::iter::once("SOURCE".len())).max().unwrap_or_default();
+    let type_width = candidates.iter()
+        .map(|candidate| candidate.content_type.as_deref().unwrap_or("-").len())
+        .chain(std::iter::once("CONTENT-TYPE".len()))
+        .max()
+        .unwrap_or_default();
+
+    if let Some(pages) = candidates.first().map(|candidate| &candidate.pages).filter(|pages| pages.len() > 1) {
+        info!("Found through {}", pages.iter().map(Url::as_str).collect::<Vec<_>>().join(" -> "));
+    }
+
+    info!("{:>3}  {:4}  {:>10}  {:type_width$}  {:source_width$}  URL", "#", "EXT", "SIZE", "CONTENT-TYPE", "SOURCE");
+
+    for (i, candidate) in candidates.iter().enumerate() {
+        let size = match (candidate.unreachable, candidate.size) {
+            (true, _) => "failed".to_string(),
+            (false, Some(size)) => size.to_string(),
+            (false, None) => "-".to_string(),
+        };
+
+        info!("{:>3}  {:4}  {:>10}  {:type_width$}  {:source_width$}  {}",
+            i + 1,
+            extension(&candidate.url),
+            size,
+            candidate.content_type.as_deref().unwrap_or("-"),
+            candidate.source,
+            candidate.url);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page() -> Url {
+        Url::parse("https://example.com/videos/page.html").unwrap()
+    }
+
+    /// url and source of the candidates
+    fn found(html: &str) -> Vec<(String, &'static str)> {
+        find_media(html, &page()).into_iter().map(|candidate| (candidate.url.to_string(), candidate.source)).collect()
+    }
+
+    fn candidate(url: &str, source: &'static str) -> (String, &'static str) {
+        (url.to_string(), source)
+    }
+
+    #[test]
+    fn find_media_in_elements_resolved_against_the_page() {
+        let html = r#"
+            <video src="clip.mp4"></video>
+            <video data-src="/lazy.webm"></video>
+            <video><source src=" https://cdn.example.com/master.m3u8 "><source data-src="../low.mp4"></video>
+        "#;
+
+        assert_eq!(found(html), vec![
+            candidate("https://example.com/videos/clip.mp4", "<video src>"),
+            candidate("https://example.com/lazy.webm", "<video data-src>"),
+            candidate("https://cdn.example.com/master.m3u8", "<source src>"),
+            candidate("https://example.com/low.mp4", "<source data-src>"),
+        ]);
+    }
+
+    #[test]
+    fn find_media_in_meta_tags_and_json_ld() {
+        let html = r#"<head>
+            <meta property="og:video:secure_url" content="https://example.com/og.mp4">
+            <script type="application/ld+json">
+                {"@graph": [{"@type": ["VideoObject"], "contentUrl": "https://example.com/ld.m3u8"}, {"@type": "ImageObject", "contentUrl": "https://example.com/image.mp4"}]}
+            </script>
+        </head>"#;
+
+        assert_eq!(found(html), vec![
+            candidate("https://example.com/og.mp4", "<meta og:video>"),
+            candidate("https://example.com/ld.m3u8", "json-ld VideoObject"),
+        ]);
+    }
+
+    #[test]
+    fn find_media_in_escaped_script_strings() {
+        let html = r#"<script>
+            var player = {"hls": "https:\/\/example.com\/stream.m3u8?token=a&b", poster: 'poster.jpg'};
+            fetch('/api/config.json');
+        </script>"#;
+
+        assert_eq!(found(html), vec![candidate("https://example.com/stream.m3u8?token=a&b", "<script>")]);
+    }
+
+    #[test]
+    fn find_media_skips_duplicates_and_other_schemes() {
+        let html = r#"
+            <video src="clip.mp4"><source src="https://example.com/videos/clip.mp4"></video>
+            <video src="blob:https://example.com/1234"></video>
+            <video src="data:video/mp4;base64,AAAA"></video>
+        "#;
+
+        assert_eq!(found(html), vec![candidate("https://example.com/videos/clip.mp4", "<video src>")]);
+    }
+
+    #[test]
+    fn find_media_sends_the_page_as_referer() {
+        let candidates = find_media(r#"<video src="clip.mp4"></video>"#, &page());
+
+        assert_eq!(candidates[0].headers.get(REFERER).unwrap(), page().as_str());
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// a file that is written to `<path>.part` and only renamed to `path` once it is complete,
+/// so an existing `path` always means a finished download
+pub struct PartFile {
+    path: PathBuf,
+    part_path: PathBuf,
+    file: File,
+    finished: bool,
+}
+
+impl PartFile {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(".part");
+        let part_path = path.with_file_name(file_name);
+
+        let file = File::create(&part_path)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            part_path,
+            file,
+            finished: false,
+        })
+    }
+
+    /// checks the written length, syncs the data if requested and moves the file into place
+    pub fn finish(mut self, expected_len: Option<u64>, fsync: bool) -> std::io::Result<()> {
+        self.file.flush()?;
+
+        let len = self.file.metadata()?.len();
+        if let Some(expected_len) = expected_len {
+            if len != expected_len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} has {} bytes but {} were expected", self.path.to_string_lossy(), len, expected_len),
+                ));
+            }
+        }
+
+        if fsync {
+            self.file.sync_all()?;
+        }
+
+        std::fs::rename(&self.part_path, &self.path)?;
+        self.finished = true;
+
+        if fsync {
+            if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+                File::open(parent)?.sync_all()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Write for PartFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for PartFile {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.part_path);
+        }
+    }
+}
+
+/// writes `content` to `path` through a [`PartFile`]
+pub fn write(path: &Path, content: &[u8], expected_len: Option<u64>, fsync: bool) -> std::io::Result<()> {
+    let mut file = PartFile::create(path)?;
+    file.write_all(content)?;
+    file.finish(expected_len, fsync)
+}
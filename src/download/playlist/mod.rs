@@ -1,22 +1,80 @@
+pub mod rebase;
 pub mod segment;
+pub mod ts;
+pub mod validate;
 
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
+use reqwest::header::HeaderMap;
+use tokio_util::sync::CancellationToken;
 use url::Url;
-use crate::options::Options;
+use crate::options::{Options, Verify};
 
-use crate::download::DownloadClient;
+use crate::download::{verify, DownloadClient, Downloaded};
+use crate::download::part_file::PartFile;
+use rebase::Rebase;
 use segment::{parse_segments, Segment};
+use ts::SYNC_BYTE;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Playlist {
     pub total_duration: f64,
     pub segments: Vec<Segment>,
+    /// the stream that was selected if the url pointed to a master playlist
+    pub variant: Option<String>,
+}
+
+impl Playlist {
+    /// the segments that start a new part, the first segment is not counted
+    pub fn discontinuities(&self) -> usize {
+        self.segments.iter().skip(1).filter(|segment| segment.discontinuity).count()
+    }
+
+    /// the playlist split at its discontinuities, one playlist per part
+    pub fn parts(&self) -> Vec<Playlist> {
+        let mut parts: Vec<Playlist> = Vec::new();
+
+        for segment in &self.segments {
+            match parts.last_mut() {
+                Some(part) if !segment.discontinuity => part.segments.push(segment.clone()),
+                _ => parts.push(Playlist { total_duration: 0., segments: vec![segment.clone()], variant: self.variant.clone() }),
+            }
+        }
+
+        for part in &mut parts {
+            part.total_duration = part.segments.iter().map(|segment| segment.duration).sum();
+        }
+
+        parts
+    }
+}
+
+/// `video.ts` is written to `video.2.ts` for the second part of a split playlist
+fn part_path(output: &Path, part: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+
+    match output.extension() {
+        Some(extension) => output.with_file_name(format!("{}.{}.{}", stem, part, extension.to_string_lossy())),
+        None => output.with_file_name(format!("{}.{}", stem, part)),
+    }
 }
 
 pub struct Stream {
     playlist_url: Url,
     bandwidth: i64,
+    /// height of the `RESOLUTION` attribute
+    height: Option<u32>,
+}
+
+/// the value of attribute `name` of a tag line like `#EXT-X-STREAM-INF:BANDWIDTH=1280000,RESOLUTION=1280x720`
+fn attribute<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let (_, attributes) = line.split_once(':')?;
+
+    attributes.split(',').find_map(|attribute| {
+        let (key, value) = attribute.split_once('=')?;
+        (key.trim() == name).then(|| value.trim().trim_matches('"'))
+    })
 }
 
 pub fn parse_playlist_master(playlist: &str, prefix: &str) -> Result<Stream, Box<dyn std::error::Error>> {
@@ -26,10 +84,10 @@ pub fn parse_playlist_master(playlist: &str, prefix: &str) -> Result<Stream, Box
 
     lines.iter().enumerate().for_each(|(i, line)| {
         if line.starts_with("#EXT-X-STREAM-INF") {
-            let search = "BANDWIDTH=";
-            let idx_start = line.find(search).unwrap();
-            let idx_end = idx_start + line[idx_start..].find(",").unwrap();
-            let bandwidth = line[idx_start + 1 + search.len()..idx_end].parse::<i64>().unwrap();
+            let bandwidth = attribute(line, "BANDWIDTH").and_then(|bandwidth| bandwidth.parse::<i64>().ok()).unwrap_or_default();
+            let height = attribute(line, "RESOLUTION")
+                .and_then(|resolution| resolution.split_once('x'))
+                .and_then(|(_, height)| height.parse::<u32>().ok());
             let uri = lines[i + 1];
             streams.push(Stream {
                 playlist_url: match Url::parse(uri) {
@@ -37,6 +95,7 @@ pub fn parse_playlist_master(playlist: &str, prefix: &str) -> Result<Stream, Box
                     Err(_) => Url::parse((prefix.to_string() + uri).as_str()).unwrap(),
                 },
                 bandwidth,
+                height,
             });
         }
     });
@@ -46,46 +105,67 @@ pub fn parse_playlist_master(playlist: &str, prefix: &str) -> Result<Stream, Box
     Ok(selected_stream)
 }
 
+/// the height of the stream that would be downloaded if `playlist_url` is a master playlist
+pub async fn selected_height(playlist_url: &Url, headers: &HeaderMap, client: &DownloadClient) -> Option<u32> {
+    let download_client = client.with_headers(headers);
+
+    let playlist = download_client.download(playlist_url).await.ok()?;
+    let playlist = String::from_utf8_lossy(&playlist);
+
+    if !playlist.contains("#EXT-X-STREAM-INF") {
+        return None;
+    }
+
+    let prefix = playlist_url.as_str().rsplit_once("/")?.0.to_string() + "/";
 
-async fn parse_playlist(playlist_url: &Url) -> Result<Playlist, Box<dyn std::error::Error>> {
-    let download_client = DownloadClient::new();
+    parse_playlist_master(&playlist, &prefix).ok()?.height
+}
 
+/// the segments of the playlist at `playlist_url`, of its best stream if it is a master playlist
+pub async fn parse_playlist(playlist_url: &Url, download_client: &DownloadClient) -> Result<Playlist, Box<dyn std::error::Error>> {
     let playlist = match download_client.download(playlist_url).await {
         Ok(playlist) => match String::from_utf8(playlist.to_vec()) {
             Ok(playlist) => playlist,
             Err(err) => {
-                eprintln!("Error parsing playlist: {}", err);
+                error!("Error parsing playlist: {}", err);
                 return Err(Box::new(err));
             }
         },
         Err(err) => {
-            eprintln!("Error downloading playlist: {}", err);
+            error!("Error downloading playlist: {}", err);
             return Err(err);
         }
     };
 
     let prefix = playlist_url.as_str().rsplit_once("/").unwrap().0.to_string() + "/";
 
+    let mut variant = None;
+
     let segments = match playlist.find("#EXT-X-STREAM-INF") {
         Some(_) => {
             let stream = match parse_playlist_master(playlist.as_str(), prefix.as_str()) {
                 Ok(stream) => stream,
                 Err(err) => {
-                    eprintln!("Error parsing master playlist: {}", err);
+                    error!("Error parsing master playlist: {}", err);
                     return Err(err);
                 }
             };
 
+            variant = Some(match stream.height {
+                Some(height) => format!("{}p, {} bps", height, stream.bandwidth),
+                None => format!("{} bps", stream.bandwidth),
+            });
+
             let playlist = match download_client.download(&stream.playlist_url).await {
                 Ok(playlist) => match String::from_utf8(playlist.to_vec()) {
                     Ok(playlist) => playlist,
                     Err(err) => {
-                        eprintln!("Error parsing playlist: {}", err);
+                        error!("Error parsing playlist: {}", err);
                         return Err(Box::new(err));
                     }
                 },
                 Err(err) => {
-                    eprintln!("Error downloading playlist: {}", err);
+                    error!("Error downloading playlist: {}", err);
                     return Err(err);
                 }
             };
@@ -97,16 +177,19 @@ async fn parse_playlist(playlist_url: &Url) -> Result<Playlist, Box<dyn std::err
 
     Ok(Playlist {
         total_duration: segments.iter().map(|segment| segment.duration).sum(),
-        segments
+        segments,
+        variant,
     })
 }
 
 
-pub async fn download_playlist(playlist_url: &Url, output: &Path, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
-    let playlist = match parse_playlist(playlist_url).await {
+pub async fn download_playlist(playlist_url: &Url, headers: &HeaderMap, output: &Path, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<Downloaded, Box<dyn std::error::Error>> {
+    let client = client.with_headers(headers);
+
+    let playlist = match parse_playlist(playlist_url, &client).await {
         Ok(playlist) => playlist,
         Err(err) => {
-            eprintln!("Error parsing playlist: {}", err);
+            error!("Error parsing playlist: {}", err);
             return Err(err);
         }
     };
@@ -119,36 +202,104 @@ pub async fn download_playlist(playlist_url: &Url, output: &Path, options: &Opti
         match std::fs::create_dir(segment_folder) {
             Ok(_) => {}
             Err(err) => {
-                eprintln!("Error creating folder: {}", err);
+                error!("Error creating folder: {}", err);
                 return Err(Box::new(err));
             }
         }
     }
     
-    segment::download_segments(&playlist, &segment_folder, options).await?;
+    segment::download_segments(&playlist, segment_folder, &client, options, cancel).await?;
 
-    // segments are downloaded, now we need to merge them
-    let mut file = match std::fs::File::create(output) {
+    // segments are downloaded, now we need to merge them, the parts of a split playlist each on their own
+    let parts = match options.split_discontinuities && playlist.discontinuities() > 0 {
+        true => playlist.parts().into_iter().enumerate().map(|(index, part)| (part, part_path(output, index + 1))).collect(),
+        false => vec![(playlist.clone(), output.to_path_buf())],
+    };
+
+    if playlist.discontinuities() > 0 {
+        match parts.len() {
+            1 => info!("Rebasing the timestamps after {} discontinuities", playlist.discontinuities()),
+            _ => info!("Splitting the playlist at {} discontinuities into {}", playlist.discontinuities(), parts.iter().map(|(_, path)| path.to_string_lossy()).collect::<Vec<_>>().join(", ")),
+        }
+    }
+
+    for (part, part_output) in &parts {
+        merge(part, segment_folder, part_output, options.fsync)?;
+
+        match options.verify {
+            Verify::Off => {}
+            // a report only lists the problems, not even an error of the check (it logs it) fails the download
+            Verify::Report => {
+                let _ = verify::check(part, segment_folder, part_output);
+            }
+            // the problems fail the download once refetching did not fix them
+            Verify::Refetch => {
+                let mut problems = verify::check(part, segment_folder, part_output)?;
+
+                if !problems.segments.is_empty() {
+                    verify::refetch(part, &problems.segments, segment_folder, part_output, options, &client, cancel).await?;
+                    problems = verify::check(part, segment_folder, part_output)?;
+                }
+
+                verify::ensure_fixed(&problems, part_output)?;
+            }
+        }
+    }
+
+    let parts = match parts.len() {
+        1 => vec![],
+        _ => parts.into_iter().map(|(_, path)| path).collect(),
+    };
+
+    Ok(Downloaded { output: output.to_path_buf(), parts, media_url: playlist_url.clone(), variant: playlist.variant, checksum: None })
+}
+
+/// concatenates the downloaded segments of `playlist` in `segment_folder` to `output`, MPEG-TS
+/// segments after a discontinuity are rebased to continue the timestamps of the ones before
+pub fn merge(playlist: &Playlist, segment_folder: &Path, output: &Path, fsync: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = match PartFile::create(output) {
         Ok(file) => file,
         Err(err) => {
-            eprintln!("Error creating file: {}", err);
+            error!("Error creating file: {}", err);
             return Err(Box::new(err));
         }
     };
 
-    for segment in playlist.segments {
-        let seg_name = folder_name.clone() + "/" + &segment.name;
-        let segment_file = match std::fs::File::open(seg_name.clone()) {
+    let mut rebase = (playlist.discontinuities() > 0).then(Rebase::default);
+    let mut expected_len = 0;
+
+    for (index, segment) in playlist.segments.iter().enumerate() {
+        let seg_name = segment_folder.join(&segment.name);
+        let segment_file = match std::fs::File::open(&seg_name) {
             Ok(file) => file,
             Err(err) => {
-                eprintln!("Error opening file ({}): {}", seg_name, err);
+                error!("Error opening file ({}): {}", seg_name.to_string_lossy(), err);
                 return Err(Box::new(err));
             }
         };
 
-        let mut content = std::io::BufReader::new(segment_file);
-        std::io::copy(&mut content, &mut file)?;
+        expected_len += segment_file.metadata()?.len();
+
+        let Some(rebase) = rebase.as_mut() else {
+            let mut content = std::io::BufReader::new(segment_file);
+            std::io::copy(&mut content, &mut file)?;
+            continue;
+        };
+
+        let mut content = std::fs::read(&seg_name)?;
+
+        // other formats are copied as they are
+        if content.first() == Some(&SYNC_BYTE) {
+            if index == 0 || segment.discontinuity {
+                rebase.start_part(&content);
+            }
+            rebase.rewrite(&mut content);
+        }
+
+        file.write_all(&content)?;
     }
 
+    file.finish(Some(expected_len), fsync)?;
+
     Ok(())
 }
@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use crate::download::playlist::ts::{pes_timestamps, read_pcr, read_timestamp, write_pcr, write_timestamp, Packet, NULL_PID, PTS_WRAP, TS_PACKET};
+
+/// `a - b` of two timestamps, a wrap in between is not counted
+fn difference(a: i64, b: i64) -> i64 {
+    match (a - b).rem_euclid(PTS_WRAP) {
+        difference if difference >= PTS_WRAP / 2 => difference - PTS_WRAP,
+        difference => difference,
+    }
+}
+
+/// a pid of the merged stream
+#[derive(Default)]
+struct Pid {
+    /// continuity counter of the last written packet
+    counter: Option<u8>,
+    /// added to the counters of the current part, so they go on from the last part
+    shift: Option<u8>,
+    /// last written timestamp in decoding order
+    timestamp: Option<i64>,
+}
+
+/// rewrites MPEG-TS segments while they are merged, so each part after an `#EXT-X-DISCONTINUITY`
+/// continues the timestamps and continuity counters of the part before instead of starting over
+#[derive(Default)]
+pub struct Rebase {
+    /// ticks added to the timestamps of the current part
+    offset: i64,
+    pids: HashMap<u16, Pid>,
+    /// latest timestamp written in decoding order
+    end: Option<i64>,
+    /// shortest time between two timestamps of a pid, the gap left between two parts
+    frame: Option<i64>,
+}
+
+impl Rebase {
+    /// a new part starts with the `segment`, its first timestamp is moved behind the last one written
+    pub fn start_part(&mut self, segment: &[u8]) {
+        let timestamps = segment.chunks_exact(TS_PACKET)
+            .filter_map(|packet| Some((packet, Packet::parse(packet)?)))
+            .filter(|(_, parsed)| parsed.payload_start && parsed.has_payload)
+            .filter_map(|(packet, parsed)| pes_timestamps(packet, parsed.payload).last().map(|start| read_timestamp(&packet[*start..])));
+
+        // the first timestamp of the part in decoding order, the others are compared to it because of wraps
+        let mut origin = None;
+        for timestamp in timestamps {
+            if origin.is_none_or(|origin| difference(timestamp, origin) < 0) {
+                origin = Some(timestamp);
+            }
+        }
+
+        self.offset = match (self.end, origin) {
+            (Some(end), Some(origin)) => difference(end + self.frame.unwrap_or(1), origin),
+            _ => 0,
+        };
+
+        for pid in self.pids.values_mut() {
+            pid.shift = None;
+        }
+    }
+
+    /// rewrites the packets of a segment of the current part in place
+    pub fn rewrite(&mut self, segment: &mut [u8]) {
+        for packet in segment.chunks_exact_mut(TS_PACKET) {
+            let Some(parsed) = Packet::parse(packet) else {
+                continue;
+            };
+            if parsed.pid == NULL_PID {
+                continue;
+            }
+
+            let pid = self.pids.entry(parsed.pid).or_default();
+
+            let shift = *pid.shift.get_or_insert_with(|| match pid.counter {
+                // a packet without payload repeats the counter of the one before
+                Some(last) => (last + parsed.has_payload as u8).wrapping_sub(parsed.counter) & 0x0F,
+                None => 0,
+            });
+            let counter = (parsed.counter + shift) & 0x0F;
+            packet[3] = (packet[3] & 0xF0) | counter;
+            pid.counter = Some(counter);
+
+            if let Some(pcr) = parsed.pcr {
+                let base = (read_pcr(&packet[pcr..]) + self.offset).rem_euclid(PTS_WRAP);
+                write_pcr(&mut packet[pcr..], base);
+            }
+
+            if !parsed.payload_start || !parsed.has_payload {
+                continue;
+            }
+
+            let mut timestamps = pes_timestamps(packet, parsed.payload).into_iter().map(|start| {
+                let timestamp = (read_timestamp(&packet[start..]) + self.offset).rem_euclid(PTS_WRAP);
+                write_timestamp(&mut packet[start..], timestamp);
+                timestamp
+            }).collect::<Vec<_>>();
+
+            // the dts follows the pts if the frames are reordered
+            let Some(decoding) = timestamps.pop() else {
+                continue;
+            };
+
+            if let Some(frame) = pid.timestamp.map(|last| difference(decoding, last)).filter(|frame| *frame > 0) {
+                self.frame = Some(self.frame.map_or(frame, |shortest| shortest.min(frame)));
+            }
+            pid.timestamp = Some(decoding);
+
+            if self.end.is_none_or(|end| difference(decoding, end) > 0) {
+                self.end = Some(decoding);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::playlist::ts::tests::packet;
+
+    /// counter, pts and pcr of the packets of `segment`
+    fn fields(segment: &[u8]) -> Vec<(u8, i64, Option<i64>)> {
+        segment.chunks_exact(TS_PACKET).map(|packet| {
+            let parsed = Packet::parse(packet).unwrap();
+            let pcr = parsed.pcr.map(|pcr| read_pcr(&packet[pcr..]));
+
+            (parsed.counter, read_timestamp(&packet[parsed.payload + 9..]), pcr)
+        }).collect()
+    }
+
+    #[test]
+    fn difference_over_a_wrap() {
+        assert_eq!(difference(5, PTS_WRAP - 5), 10);
+        assert_eq!(difference(PTS_WRAP - 5, 5), -10);
+        assert_eq!(difference(3000, 9000), -6000);
+    }
+
+    #[test]
+    fn rewrite_continues_after_a_discontinuity() {
+        let mut rebase = Rebase::default();
+
+        let mut first = [packet(256, 0, Some(1000), Some(0)), packet(256, 1, Some(4000), None), packet(256, 2, Some(7000), None)].concat();
+        rebase.start_part(&first);
+        rebase.rewrite(&mut first);
+        assert_eq!(fields(&first), vec![(0, 1000, Some(0)), (1, 4000, None), (2, 7000, None)]);
+
+        // the timestamps and counters start over in the next part
+        let mut second = [packet(256, 0, Some(900_000), Some(899_000)), packet(256, 1, Some(903_000), None)].concat();
+        rebase.start_part(&second);
+        rebase.rewrite(&mut second);
+        assert_eq!(fields(&second), vec![(3, 10_000, Some(9000)), (4, 13_000, None)]);
+
+        // and a part before a wrap is moved behind the last one as well
+        let mut third = [packet(256, 7, Some(PTS_WRAP - 1000), None)].concat();
+        rebase.start_part(&third);
+        rebase.rewrite(&mut third);
+        assert_eq!(fields(&third), vec![(5, 16_000, None)]);
+    }
+}
@@ -1,11 +1,16 @@
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use reqwest::header::HeaderMap;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
-use crate::download::DownloadClient;
-use crate::download::playlist::Playlist;
+use crate::download::{part_file, DownloadClient};
+use crate::download::concurrency::{self, Concurrency};
+use crate::download::playlist::{validate, Playlist};
+use crate::error::cancelled_error::CancelledError;
 use crate::options::Options;
+use crate::report;
 
 #[derive(Debug, Clone)]
 pub struct Segment {
@@ -13,6 +18,8 @@ pub struct Segment {
     pub uri: Url,
     pub duration: f64,
     pub downloaded: bool,
+    /// follows an `#EXT-X-DISCONTINUITY`, its timestamps and codec parameters may start over
+    pub discontinuity: bool,
 }
 
 
@@ -35,88 +42,92 @@ impl Clone for SegmentDownloadArgs {
 }
 
 impl Segment {
-    async fn finished(&mut self, args: &SegmentDownloadArgs) {
+    async fn finished(&mut self, args: &SegmentDownloadArgs, concurrency: Option<usize>) {
         let mut downloaded_duration = args.downloaded_duration.lock().await;
         let mut downloaded_segments = args.downloaded_segments.lock().await;
         *downloaded_segments += 1;
         *downloaded_duration += self.duration;
 
-        print_time(*downloaded_duration);
-        print!(" / ");
-        print_time(args.total_duration);
-        print!(" ({:5.2}%)", (*downloaded_duration / args.total_duration) * 100.0);
-
-        print!("\t {:width$} / {:width$} segs ({:5.2}%)", 
+        info!("{} / {} ({:5.2}%)\t {:width$} / {:width$} segs ({:5.2}%)\t {}{}",
+            format_time(*downloaded_duration),
+            format_time(args.total_duration),
+            (*downloaded_duration / args.total_duration) * 100.0,
             *downloaded_segments, 
             args.total_segments, 
             (*downloaded_segments as f64 / args.total_segments as f64) * 100.0,
+            self.name,
+            concurrency::format_concurrency(concurrency),
             width = args.total_segments.to_string().len()
         );
-
-        print!("\t {}", self.name);
-        println!();
+        report::progress(*downloaded_segments as u64, args.total_segments as u64);
 
         drop(downloaded_segments);
         drop(downloaded_duration);
     }
 
 
-    async fn download(&mut self, folder_name: Arc<PathBuf>, client: Arc<DownloadClient>) -> Result<(), Box<dyn std::error::Error + Send>> {
+    /// downloads the segment to `folder_name` and returns the number of bytes downloaded
+    async fn download(&mut self, folder_name: Arc<PathBuf>, client: Arc<DownloadClient>, cancel: CancellationToken, fsync: bool) -> Result<u64, Box<dyn std::error::Error + Send>> {
         if self.downloaded {
-            return Ok(());
+            return Ok(0);
         }
 
         let seg_path = folder_name.join(&self.name);
         if seg_path.exists() {
             self.downloaded = true;
-            return Ok(());
+            return Ok(0);
         }
 
 
-        let bytes = match client.download(&self.uri).await {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                eprintln!("Error downloading segment: {}", err);
-                return Err(err);
-            }
-        };
+        let no_headers = HeaderMap::new();
 
-        let mut file = match std::fs::File::create(seg_path) {
-            Ok(file) => file,
-            Err(err) => {
-                eprintln!("Error creating file: {}", err);
-                return Err(Box::new(err));
+        // a cancelled request is dropped before anything is written to disk
+        let (headers, bytes) = tokio::select! {
+            _ = cancel.cancelled() => return Err(Box::new(CancelledError)),
+            result = client.download_response(&self.uri, &no_headers) => match result {
+                Ok(response) => response,
+                Err(err) => {
+                    error!("Error downloading segment: {}", err);
+                    return Err(err);
+                }
             }
         };
 
-        let mut content = std::io::Cursor::new(bytes);
-        match std::io::copy(&mut content, &mut file) {
-            Ok(_) => {}
-            Err(err) => {
-                eprintln!("Error writing to file: {}", err);
-                return Err(Box::new(err));
-            }
+        // an invalid body is not written, so the segment is retried
+        if let Err(err) = validate::segment(&self.name, &headers, &bytes) {
+            error!("Error downloading segment: {}", err);
+            return Err(Box::new(err));
+        }
+
+        if let Err(err) = part_file::write(&seg_path, &bytes, Some(bytes.len() as u64), fsync) {
+            error!("Error writing to file: {}", err);
+            return Err(Box::new(err));
         }
 
         self.downloaded = true;
 
-        Ok(())
+        Ok(bytes.len() as u64)
     }
 }
 
-fn print_time(seconds: f64) {
+pub fn format_time(seconds: f64) -> String {
     let hours = seconds as i64 / 3600;
     let minutes = (seconds as i64 % 3600) / 60;
     let seconds = seconds as i64 % 60;
 
-    print!("{:02}:{:02}:{:02}", hours, minutes, seconds);
+    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
 }
 
 pub async fn parse_segments(playlist: &str, prefix: &str) -> Result<Vec<Segment>, Box<dyn std::error::Error>> {
     let mut segments = Vec::new();
     let lines = playlist.lines().collect::<Vec<&str>>();
+    let mut discontinuity = false;
 
     lines.iter().enumerate().for_each(|(i, line)| {
+        if line.trim() == "#EXT-X-DISCONTINUITY" {
+            discontinuity = true;
+        }
+
         if line.starts_with("#EXTINF") {
             let idx_start = line.find(":").unwrap();
             let idx_end = line.find(",").unwrap();
@@ -134,6 +145,7 @@ pub async fn parse_segments(playlist: &str, prefix: &str) -> Result<Vec<Segment>
                 uri,
                 duration,
                 downloaded: false,
+                discontinuity: std::mem::take(&mut discontinuity),
             });
         }
     });
@@ -141,11 +153,11 @@ pub async fn parse_segments(playlist: &str, prefix: &str) -> Result<Vec<Segment>
     Ok(segments)
 }
 
-pub async fn download_segments(playlist: &Playlist, segment_folder: &Path, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
-    let semaphore = Arc::new(tokio::sync::Semaphore::new(options.max_parallel_downloads));
-    let downloaded_duration = Arc::new(Mutex::new(0.0 as f64));
-    let downloaded_segments = Arc::new(Mutex::new(0 as i32));
-    let http_client = Arc::new(DownloadClient::new());
+pub async fn download_segments(playlist: &Playlist, segment_folder: &Path, client: &DownloadClient, options: &Options, cancel: &CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    let concurrency = Arc::new(Concurrency::new(options));
+    let downloaded_duration = Arc::new(Mutex::new(0.0_f64));
+    let downloaded_segments = Arc::new(Mutex::new(0_i32));
+    let http_client = Arc::new(client.clone());
     let segment_folder = Arc::new(segment_folder.to_owned());
 
     let mut segments = playlist.segments.to_owned();
@@ -158,27 +170,44 @@ pub async fn download_segments(playlist: &Playlist, segment_folder: &Path, optio
     };
 
     let mut tries = 0;
+    // segments whose task failed outright, they are not retried
+    let mut failed = 0;
     
-    while segments.len() > 0 && tries < options.max_download_retries {
+    while !segments.is_empty() && tries < options.max_download_retries && !cancel.is_cancelled() {
         let tasks = segments.into_iter().map(
             |mut segment| {
                 let args = args.clone();
-                let semaphore = Arc::clone(&semaphore);
+                let concurrency = Arc::clone(&concurrency);
                 let segment_folder = Arc::clone(&segment_folder);
                 let http_client = Arc::clone(&http_client);
+                let scheduler = Arc::clone(&options.scheduler);
+                let cancel = cancel.clone();
+                let fsync = options.fsync;
                 tokio::spawn(async move {
-                    let permit = semaphore.acquire().await.unwrap();
-
-                    if let Err(err) = segment.download(segment_folder, http_client).await {
-                        return Err(err);
+                    let permit = tokio::select! {
+                        _ = cancel.cancelled() => return Err(Box::new(CancelledError) as Box<dyn std::error::Error + Send>),
+                        permit = concurrency.acquire() => permit,
+                    };
+                    let slot = tokio::select! {
+                        _ = cancel.cancelled() => return Err(Box::new(CancelledError) as Box<dyn std::error::Error + Send>),
+                        slot = scheduler.acquire(&segment.uri) => slot,
+                    };
+
+                    let result = segment.download(segment_folder, http_client, cancel, fsync).await;
+                    std::mem::drop(slot);
+
+                    match result {
+                        Ok(bytes) => concurrency.finished(permit, bytes),
+                        Err(err) if err.is::<CancelledError>() => return Err(err),
+                        // the segment is not downloaded, so it is retried in the next round
+                        Err(err) => concurrency.failed(permit, err.as_ref()),
                     }
 
-                    std::mem::drop(permit);
                     if segment.downloaded {
-                        segment.finished(&args).await;
+                        segment.finished(&args, concurrency.current()).await;
                     }
 
-                    Ok(segment)
+                    Ok::<_, Box<dyn std::error::Error + Send>>(segment)
                 })
             }
         ).collect::<Vec<_>>();
@@ -190,22 +219,37 @@ pub async fn download_segments(playlist: &Playlist, segment_folder: &Path, optio
                 Ok(Ok(segment)) if !segment.downloaded => {
                     segments.push(segment);
                 },
+                Ok(Err(err)) if err.is::<CancelledError>() => {},
                 Ok(Err(err)) => {
-                    eprintln!("Error downloading segment: {}", err);
+                    error!("Error downloading segment: {}", err);
+                    failed += 1;
                 },
                 Err(err) => {
-                    eprintln!("Error waiting for task: {}", err);
+                    error!("Error waiting for task: {}", err);
+                    failed += 1;
                 },
                 _ => {}
             }
         }
 
-        if segments.len() > 0 {
-            println!("Retrying {} segments", segments.len());
+        if !segments.is_empty() && !cancel.is_cancelled() && tries + 1 < options.max_download_retries {
+            info!("Retrying {} segments", segments.len());
         }
 
         tries += 1;
     }
 
+    if cancel.is_cancelled() {
+        info!("Download cancelled, the finished segments are kept in {}", segment_folder.to_string_lossy());
+        return Err(Box::new(CancelledError));
+    }
+
+    // merging the rest would leave holes in the output
+    let missing = failed + segments.len();
+    if missing > 0 {
+        error!("Gave up on {} segments after {} tries, the finished segments are kept in {}", missing, tries, segment_folder.to_string_lossy());
+        return Err(format!("{} segments could not be downloaded", missing).into());
+    }
+
     Ok(())
 }
@@ -0,0 +1,163 @@
+/// size of an MPEG-TS packet, which starts with `SYNC_BYTE`
+pub const TS_PACKET: usize = 188;
+pub const SYNC_BYTE: u8 = 0x47;
+/// timestamps are 33 bit and start over at this value
+pub const PTS_WRAP: i64 = 1 << 33;
+/// pid of the packets that only fill up the bitrate
+pub const NULL_PID: u16 = 0x1FFF;
+
+/// the header fields of a transport stream packet
+pub struct Packet {
+    pub pid: u16,
+    pub payload_start: bool,
+    pub counter: u8,
+    pub has_payload: bool,
+    /// the discontinuity indicator of the adaptation field, the counter and timestamps may start over
+    pub discontinuity: bool,
+    /// start of the payload after the adaptation field
+    pub payload: usize,
+    /// offset of the pcr in the adaptation field
+    pub pcr: Option<usize>,
+}
+
+impl Packet {
+    /// `None` unless `packet` is a full packet that starts with the sync byte
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < TS_PACKET || packet[0] != SYNC_BYTE {
+            return None;
+        }
+
+        let control = (packet[3] >> 4) & 0x03;
+        let (payload, discontinuity, pcr) = match control & 0x02 {
+            0 => (4, false, None),
+            _ => {
+                let len = packet[4] as usize;
+                (5 + len, len > 0 && packet[5] & 0x80 != 0, (len >= 7 && packet[5] & 0x10 != 0).then_some(6))
+            }
+        };
+
+        Some(Self {
+            pid: u16::from_be_bytes([packet[1] & 0x1F, packet[2]]),
+            payload_start: packet[1] & 0x40 != 0,
+            counter: packet[3] & 0x0F,
+            has_payload: control & 0x01 != 0 && payload < TS_PACKET,
+            discontinuity,
+            payload,
+            pcr,
+        })
+    }
+}
+
+/// where the PTS and DTS of a packet that starts a PES are, streams without them are skipped
+pub fn pes_timestamps(packet: &[u8], payload: usize) -> Vec<usize> {
+    let Some(pes) = packet.get(payload..payload + 9) else {
+        return vec![];
+    };
+    // padding, private stream 2, ecm, emm, dsmcc and h.222 type e streams have no optional header
+    if pes[0..3] != [0, 0, 1] || [0xBC, 0xBE, 0xBF, 0xF0, 0xF1, 0xFF, 0xF2, 0xF8].contains(&pes[3]) {
+        return vec![];
+    }
+
+    let timestamps = match pes[7] >> 6 {
+        2 => vec![payload + 9],
+        3 => vec![payload + 9, payload + 14],
+        _ => vec![],
+    };
+
+    timestamps.into_iter().filter(|start| start + 5 <= TS_PACKET).collect()
+}
+
+pub fn read_timestamp(bytes: &[u8]) -> i64 {
+    (((bytes[0] >> 1) & 0x07) as i64) << 30
+        | (bytes[1] as i64) << 22
+        | ((bytes[2] >> 1) as i64) << 15
+        | (bytes[3] as i64) << 7
+        | (bytes[4] >> 1) as i64
+}
+
+/// writes a timestamp and keeps the prefix and marker bits around it
+pub fn write_timestamp(bytes: &mut [u8], timestamp: i64) {
+    bytes[0] = (bytes[0] & 0xF1) | ((timestamp >> 29) & 0x0E) as u8;
+    bytes[1] = (timestamp >> 22) as u8;
+    bytes[2] = ((timestamp >> 14) & 0xFE) as u8 | 0x01;
+    bytes[3] = (timestamp >> 7) as u8;
+    bytes[4] = ((timestamp << 1) & 0xFE) as u8 | 0x01;
+}
+
+/// the 33 bit base of a pcr, the extension is left out
+pub fn read_pcr(bytes: &[u8]) -> i64 {
+    (bytes[0] as i64) << 25
+        | (bytes[1] as i64) << 17
+        | (bytes[2] as i64) << 9
+        | (bytes[3] as i64) << 1
+        | (bytes[4] >> 7) as i64
+}
+
+/// writes the base of a pcr and keeps the reserved bits and the extension
+pub fn write_pcr(bytes: &mut [u8], base: i64) {
+    bytes[0] = (base >> 25) as u8;
+    bytes[1] = (base >> 17) as u8;
+    bytes[2] = (base >> 9) as u8;
+    bytes[3] = (base >> 1) as u8;
+    bytes[4] = (bytes[4] & 0x7F) | ((base & 0x01) << 7) as u8;
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// a packet of `pid` with a payload, starting a video PES with `pts` if there is one and with a
+    /// `pcr` in its adaptation field
+    pub fn packet(pid: u16, counter: u8, pts: Option<i64>, pcr: Option<i64>) -> Vec<u8> {
+        let mut packet = vec![0xFF; TS_PACKET];
+        packet[0] = SYNC_BYTE;
+        packet[1] = (pts.is_some() as u8) << 6 | (pid >> 8) as u8;
+        packet[2] = pid as u8;
+        packet[3] = 0x10 | counter;
+
+        let mut payload = 4;
+        if let Some(pcr) = pcr {
+            packet[3] |= 0x20;
+            packet[4..12].copy_from_slice(&[7, 0x10, 0, 0, 0, 0, 0x7E, 0]);
+            write_pcr(&mut packet[6..], pcr);
+            payload = 12;
+        }
+
+        if let Some(pts) = pts {
+            packet[payload..payload + 14].copy_from_slice(&[0, 0, 1, 0xE0, 0, 0, 0x80, 0x80, 5, 0x21, 0, 1, 0, 1]);
+            write_timestamp(&mut packet[payload + 9..], pts);
+        }
+
+        packet
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        for timestamp in [0, 1, 90_000, 0x1_2345_6789, PTS_WRAP - 1] {
+            let mut bytes = [0x31, 0, 1, 0, 1];
+            write_timestamp(&mut bytes, timestamp);
+
+            assert_eq!(read_timestamp(&bytes), timestamp);
+            // the prefix and the marker bits stay
+            assert_eq!(bytes[0] & 0xF1, 0x31);
+            assert_eq!(bytes[2] & 0x01, 1);
+            assert_eq!(bytes[4] & 0x01, 1);
+        }
+    }
+
+    #[test]
+    fn parse_finds_the_payload_and_pcr() {
+        let packet = packet(256, 3, Some(1000), Some(PTS_WRAP - 1));
+
+        let parsed = Packet::parse(&packet).unwrap();
+        assert_eq!((parsed.pid, parsed.counter, parsed.payload_start, parsed.has_payload), (256, 3, true, true));
+        assert_eq!((parsed.payload, parsed.pcr), (12, Some(6)));
+        assert_eq!(read_pcr(&packet[6..]), PTS_WRAP - 1);
+        assert_eq!(pes_timestamps(&packet, parsed.payload), vec![21]);
+        assert_eq!(read_timestamp(&packet[21..]), 1000);
+
+        // neither a short packet nor one without the sync byte
+        assert!(Packet::parse(&packet[..100]).is_none());
+        assert!(Packet::parse(&[&[0][..], &packet[1..]].concat()).is_none());
+    }
+}
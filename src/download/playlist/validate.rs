@@ -0,0 +1,174 @@
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+
+use crate::download::playlist::ts::{SYNC_BYTE, TS_PACKET};
+use crate::error::segment_error::SegmentError;
+
+/// smaller bodies can not hold a single packet or a useful MP4 fragment
+const MIN_SIZE: usize = TS_PACKET;
+/// content types of error pages, never of media
+const ERROR_TYPES: [&str; 5] = ["text/html", "application/xhtml+xml", "application/json", "application/xml", "text/xml"];
+
+/// checks that the body of segment `name` is MPEG-TS, fragmented MP4 or packed audio, so an error
+/// page or a cut off body is retried instead of ending up in the merged file
+pub fn segment(name: &str, headers: &HeaderMap, bytes: &[u8]) -> Result<(), SegmentError> {
+    let invalid = |reason: String| SegmentError { name: name.to_string(), reason };
+
+    let content_type = headers.get(CONTENT_TYPE).and_then(|content_type| content_type.to_str().ok()).unwrap_or_default();
+    let mime = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if ERROR_TYPES.contains(&mime.as_str()) {
+        return Err(invalid(format!("content type is {}", mime)));
+    }
+
+    if bytes.len() < MIN_SIZE {
+        return Err(invalid(format!("only {} bytes", bytes.len())));
+    }
+
+    if bytes[0] == SYNC_BYTE {
+        return transport_stream(bytes).map_err(invalid);
+    }
+
+    // packed audio starts with an ID3 tag or an ADTS header
+    if bytes.starts_with(b"ID3") || (bytes[0] == 0xFF && bytes[1] & 0xF0 == 0xF0) {
+        return Ok(());
+    }
+
+    if box_type(bytes).is_some() {
+        return boxes(bytes).map_err(invalid);
+    }
+
+    Err(invalid("neither MPEG-TS nor MP4".to_string()))
+}
+
+/// every full packet has to start with the sync byte, a partial last packet is left to the player
+fn transport_stream(bytes: &[u8]) -> Result<(), String> {
+    match bytes.chunks_exact(TS_PACKET).position(|packet| packet[0] != SYNC_BYTE) {
+        Some(packet) => Err(format!("no sync byte at offset {}", packet * TS_PACKET)),
+        None => Ok(()),
+    }
+}
+
+/// the type of the box at the start of `bytes` if it is printable, like `moof` or `url `
+pub fn box_type(bytes: &[u8]) -> Option<&str> {
+    let box_type = bytes.get(4..8)?;
+
+    box_type.iter().all(|byte| byte.is_ascii_alphanumeric() || *byte == b' ')
+        .then(|| std::str::from_utf8(box_type).ok())
+        .flatten()
+}
+
+/// the top level boxes have to cover the body exactly
+fn boxes(bytes: &[u8]) -> Result<(), String> {
+    let mut offset = 0;
+
+    while offset < bytes.len() {
+        let rest = &bytes[offset..];
+        let Some(box_type) = box_type(rest) else {
+            return Err(format!("no box at offset {}", offset));
+        };
+
+        let (size, header) = match u32::from_be_bytes(rest[0..4].try_into().unwrap()) {
+            // the box extends to the end of the file
+            0 => (rest.len() as u64, 8),
+            1 => match rest.get(8..16) {
+                Some(size) => (u64::from_be_bytes(size.try_into().unwrap()), 16),
+                None => return Err(format!("truncated {} box at offset {}", box_type, offset)),
+            },
+            size => (size as u64, 8),
+        };
+
+        if size < header {
+            return Err(format!("{} box at offset {} has size {}", box_type, offset, size));
+        }
+        if size > rest.len() as u64 {
+            return Err(format!("truncated {} box at offset {}, {} of {} bytes", box_type, offset, rest.len(), size));
+        }
+
+        offset += size as usize;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn packets(count: usize) -> Vec<u8> {
+        (0..count).flat_map(|_| {
+            let mut packet = vec![0xFF; TS_PACKET];
+            packet[0] = SYNC_BYTE;
+            packet
+        }).collect()
+    }
+
+    fn mp4_box(box_type: &[u8; 4], size: u32, body: usize) -> Vec<u8> {
+        let mut bytes = size.to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend(std::iter::repeat_n(0, body));
+        bytes
+    }
+
+    #[test]
+    fn accepts_transport_stream() {
+        assert!(segment("seg.ts", &HeaderMap::new(), &packets(3)).is_ok());
+
+        // a partial last packet is left to the player
+        let mut bytes = packets(2);
+        bytes.extend_from_slice(&[SYNC_BYTE, 0, 0]);
+        assert!(segment("seg.ts", &HeaderMap::new(), &bytes).is_ok());
+    }
+
+    #[test]
+    fn rejects_lost_sync() {
+        let mut bytes = packets(3);
+        bytes[TS_PACKET * 2] = 0;
+
+        let err = segment("seg.ts", &HeaderMap::new(), &bytes).unwrap_err();
+        assert_eq!(err.reason, "no sync byte at offset 376");
+    }
+
+    #[test]
+    fn rejects_error_pages_and_short_bodies() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+        assert_eq!(segment("seg.ts", &headers, &packets(3)).unwrap_err().reason, "content type is text/html");
+
+        assert_eq!(segment("seg.ts", &HeaderMap::new(), &packets(1)[..100]).unwrap_err().reason, "only 100 bytes");
+
+        let page = b"<html><body>not found</body></html>".repeat(10);
+        assert!(segment("seg.ts", &HeaderMap::new(), &page).is_err());
+    }
+
+    #[test]
+    fn accepts_packed_audio() {
+        let mut bytes = b"ID3".to_vec();
+        bytes.resize(MIN_SIZE, 0);
+        assert!(segment("seg.aac", &HeaderMap::new(), &bytes).is_ok());
+
+        let mut bytes = vec![0xFF, 0xF1];
+        bytes.resize(MIN_SIZE, 0);
+        assert!(segment("seg.aac", &HeaderMap::new(), &bytes).is_ok());
+    }
+
+    #[test]
+    fn checks_mp4_boxes() {
+        let mut bytes = mp4_box(b"moof", 100, 92);
+        bytes.extend(mp4_box(b"mdat", 200, 192));
+        assert!(segment("seg.m4s", &HeaderMap::new(), &bytes).is_ok());
+
+        bytes.truncate(250);
+        assert_eq!(segment("seg.m4s", &HeaderMap::new(), &bytes).unwrap_err().reason, "truncated mdat box at offset 100, 150 of 200 bytes");
+
+        let mut bytes = mp4_box(b"moof", 4, 192);
+        bytes.extend(mp4_box(b"mdat", 0, 100));
+        assert_eq!(segment("seg.m4s", &HeaderMap::new(), &bytes).unwrap_err().reason, "moof box at offset 0 has size 4");
+    }
+
+    #[test]
+    fn mp4_box_to_the_end() {
+        let mut bytes = mp4_box(b"moof", 8, 0);
+        bytes.extend(mp4_box(b"mdat", 0, 300));
+        assert!(segment("seg.m4s", &HeaderMap::new(), &bytes).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::download::host_matches;
+
+/// limits the bytes per second, a request may take more than there is and the next ones wait
+/// until the debt is paid back, so large chunks do not need to be split
+pub struct TokenBucket {
+    rate: u64,
+    /// available bytes and when they were last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self { rate, state: Mutex::new((rate as f64, Instant::now())) }
+    }
+
+    /// takes `bytes` and returns how long to wait until they are paid for
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+
+        let now = Instant::now();
+        // at most a second worth of bytes is saved up, so an idle limit does not allow a burst
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        *last = now;
+        *tokens -= bytes as f64;
+
+        match *tokens < 0. {
+            true => Duration::from_secs_f64(-*tokens / self.rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// the global download rate limit and the limits of single hosts, shared by all downloads of a run
+pub struct RateLimiter {
+    global: Option<TokenBucket>,
+    hosts: Vec<(String, TokenBucket)>,
+}
+
+impl RateLimiter {
+    /// `hosts` are host patterns like `*.example.com` with their limit, the first matching one is used
+    pub fn new(global: Option<u64>, hosts: Vec<(String, u64)>) -> Self {
+        Self {
+            global: global.map(TokenBucket::new),
+            hosts: hosts.into_iter().map(|(host, rate)| (host, TokenBucket::new(rate))).collect(),
+        }
+    }
+
+    /// waits until `bytes` downloaded from `url` are within the limits
+    pub async fn acquire(&self, url: &Url, bytes: usize) {
+        let host = self.hosts.iter().find(|(host, _)| host_matches(host, url)).map(|(_, bucket)| bucket);
+
+        let wait = self.global.iter().chain(host).map(|bucket| bucket.take(bytes)).max().unwrap_or_default();
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl std::fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("global", &self.global.as_ref().map(|bucket| bucket.rate))
+            .field("hosts", &self.hosts.iter().map(|(host, bucket)| (host, bucket.rate)).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// bytes per second of a rate like `500K`, `5M` or `1.5G` (powers of 1024), a plain number is bytes
+pub fn parse_rate(rate: &str) -> Result<u64, String> {
+    let rate = rate.trim();
+    let number = rate.trim_end_matches(['b', 'B']);
+
+    let (number, unit) = match number.char_indices().last() {
+        Some((i, unit)) if unit.is_ascii_alphabetic() => (&number[..i], unit.to_ascii_uppercase()),
+        _ => (number, ' '),
+    };
+
+    let factor = match unit {
+        ' ' => 1,
+        'K' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => return Err(format!("Unknown unit {} in rate {}, use K, M or G", unit, rate)),
+    };
+
+    match number.parse::<f64>() {
+        Ok(number) if number > 0. => Ok(((number * factor as f64) as u64).max(1)),
+        _ => Err(format!("Invalid rate {}, use something like 500K or 5M", rate)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_units() {
+        assert_eq!(parse_rate("500"), Ok(500));
+        assert_eq!(parse_rate("500K"), Ok(500 * 1024));
+        assert_eq!(parse_rate("5m"), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_rate("1.5G"), Ok(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_rate(" 2MB "), Ok(2 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_rate_tiny_is_one_byte() {
+        assert_eq!(parse_rate("0.0001"), Ok(1));
+    }
+
+    #[test]
+    fn parse_rate_invalid() {
+        assert!(parse_rate("").is_err());
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("-5K").is_err());
+        assert!(parse_rate("5T").is_err());
+        assert!(parse_rate("fast").is_err());
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use url::Url;
+
+/// limits of a single host, created on its first request
+struct Host {
+    /// not set if the connections to a host are not limited
+    connections: Option<Arc<Semaphore>>,
+    /// earliest start of the next request
+    next_request: tokio::sync::Mutex<Instant>,
+}
+
+/// decides when the segment and range requests may start, shared by all downloads of a run, so
+/// parallel batch entries and daemon jobs together stay within the limits of each host
+pub struct Scheduler {
+    max_connections_per_host: Option<usize>,
+    request_delay: Duration,
+    hosts: Mutex<HashMap<String, Arc<Host>>>,
+}
+
+/// allows a request to run, the connection is given back to the host when it is dropped
+pub struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Scheduler {
+    pub fn new(max_connections_per_host: Option<usize>, request_delay: Duration) -> Self {
+        Self { max_connections_per_host, request_delay, hosts: Mutex::new(HashMap::new()) }
+    }
+
+    fn host(&self, url: &Url) -> Arc<Host> {
+        let mut hosts = self.hosts.lock().unwrap();
+
+        let host = hosts.entry(url.host_str().unwrap_or_default().to_string()).or_insert_with(|| Arc::new(Host {
+            connections: self.max_connections_per_host.map(|max| Arc::new(Semaphore::new(max))),
+            next_request: tokio::sync::Mutex::new(Instant::now()),
+        }));
+
+        Arc::clone(host)
+    }
+
+    /// waits until the host of `url` has a free connection and the delay since its last request passed
+    pub async fn acquire(&self, url: &Url) -> Slot {
+        let host = self.host(url);
+
+        let permit = match &host.connections {
+            Some(connections) => Some(Arc::clone(connections).acquire_owned().await.unwrap()),
+            None => None,
+        };
+
+        if !self.request_delay.is_zero() {
+            // the lock is held while waiting, so the waiting requests start one delay apart
+            let mut next_request = host.next_request.lock().await;
+            tokio::time::sleep_until(*next_request).await;
+            *next_request = Instant::now() + self.request_delay;
+        }
+
+        Slot { _permit: permit }
+    }
+}
+
+impl std::fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("max_connections_per_host", &self.max_connections_per_host)
+            .field("request_delay", &self.request_delay)
+            .finish()
+    }
+}
@@ -1,135 +1,309 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use async_recursion::async_recursion;
+use reqwest::header::{HeaderMap, HeaderValue, REFERER};
+use tokio_util::sync::CancellationToken;
 use url::Url;
-use crate::download::{DownloadClient, playlist, video};
+use crate::download::{DownloadClient, Downloaded, extractor, output, page, playlist, video};
+use crate::download::output::Metadata;
+use crate::download::page::Candidate;
+use crate::error::exists_error::ExistsError;
 use crate::options::Options;
 
-async fn find_video_or_playlist(url: &url::Url) -> Result<Url, Box<dyn std::error::Error>> {
-    let download_client = DownloadClient::new();
+/// how many levels of iframes are followed to find a media url
+const MAX_FRAME_DEPTH: usize = 3;
 
-    let html = match download_client.download(url).await {
-        Ok(html) => String::from_utf8(html.to_vec()).unwrap(),
+/// searches the page for media urls and if it has none its iframes, depth first,
+/// `pages` are the pages that led to this one and end with it
+#[async_recursion(?Send)]
+async fn search_page(client: &DownloadClient, pages: &mut Vec<Url>, visited: &mut Vec<Url>) -> Result<Vec<Candidate>, Box<dyn std::error::Error>> {
+    let url = pages.last().unwrap().clone();
+    visited.push(url.clone());
+
+    // an iframe is requested like the browser does it, with the embedding page as referer
+    let mut headers = HeaderMap::new();
+    if let Some(Ok(referer)) = pages.iter().rev().nth(1).map(|referer| HeaderValue::from_str(referer.as_str())) {
+        headers.insert(REFERER, referer);
+    }
+
+    let html = match client.download_header(&url, &headers).await {
+        Ok(html) => String::from_utf8_lossy(&html).to_string(),
         Err(err) => {
-            eprintln!("Error downloading html: {}", err);
-            return Err(err);
+            error!("Error downloading html: {}", err);
+            return Err(err as Box<dyn std::error::Error>);
         }
     };
 
-    let get_string_around_index = |index: usize| -> String {
-        let is_quote = |char: char| char == '\'' || char == '\"';
-        let start = html[..index].rfind(is_quote).unwrap() + 1;
-        let end = index + html[index..].find(is_quote).unwrap();
-        html[start..end].to_string()
-    };
+    let mut candidates = page::find_media(&html, &url);
+    if !candidates.is_empty() {
+        let title = page::find_title(&html);
+        candidates.iter_mut().for_each(|candidate| {
+            candidate.pages = pages.clone();
+            candidate.title = title.clone();
+        });
+        return Ok(candidates);
+    }
 
-    let video_url = match html.find(".m3u8") {
-        Some(index) => {
-            println!("Found playlist url in page");
-            get_string_around_index(index)
+    if pages.len() > MAX_FRAME_DEPTH {
+        return Ok(candidates);
+    }
+
+    for frame in page::find_frames(&html, &url) {
+        if visited.contains(&frame) {
+            continue;
         }
-        None => {
-            println!("No playlist url found in page searching for video");
-            match html.find(".mp4") {
-                Some(index) => {
-                    println!("Found video url in page");
-                    get_string_around_index(index)
-                }
-                None => {
-                    eprintln!("No video or playlist found in page");
-                    return Err("No video or playlist found".into());
+
+        info!("Searching iframe {}", frame);
+
+        pages.push(frame);
+        let result = search_page(client, pages, visited).await;
+        pages.pop();
+
+        match result {
+            Ok(mut candidates) if !candidates.is_empty() => {
+                // the embedding page names the video better than the player in its iframe
+                if let Some(title) = page::find_title(&html) {
+                    candidates.iter_mut().for_each(|candidate| candidate.title = Some(title.clone()));
                 }
+                return Ok(candidates);
             }
+            Ok(_) => {}
+            // a broken iframe does not stop the search in the others
+            Err(err) => error!("Error searching iframe: {}", err),
         }
+    }
+
+    Ok(candidates)
+}
+
+/// all media urls of `url`, probed and ranked from best to worst, found by the extractor
+/// registered for the url or else by searching the page and its iframes
+pub async fn find_candidates(url: &Url, client: &DownloadClient) -> Result<Vec<Candidate>, Box<dyn std::error::Error>> {
+
+    let mut candidates = match extractor::find(url) {
+        Some(extractor) => {
+            let descriptors = match extractor.extract(client, url).await {
+                Ok(descriptors) => descriptors,
+                Err(err) => {
+                    error!("Error extracting media with {}: {}", extractor.name(), err);
+                    return Err(err);
+                }
+            };
+
+            descriptors.into_iter()
+                .flat_map(|descriptor| Candidate::from_descriptor(descriptor, extractor.name()))
+                .collect()
+        }
+        None => search_page(client, &mut vec![url.clone()], &mut Vec::new()).await?,
     };
 
-    Ok(Url::parse(&video_url).unwrap())
+    page::probe(client, &mut candidates).await;
+    page::rank(&mut candidates);
+
+    Ok(candidates)
 }
 
-async fn download_video(url: &Url, output: &Path, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
-    let path = url.path();
-    let file_extension = path.split('.').last().unwrap_or("");
-    match file_extension {
-        "mp4" => {
-            println!("Downloading mp4 file");
-            match video::download_video(url, output, options).await {
-                Ok(_) => {}
+async fn find_video_or_playlist(url: &url::Url, options: &Options, client: &DownloadClient) -> Result<Candidate, Box<dyn std::error::Error>> {
+    let candidates = find_candidates(url, client).await?;
+
+    if candidates.is_empty() {
+        error!("No video or playlist found in page");
+        return Err("No video or playlist found".into());
+    }
+
+    // a single direct url is not worth a table
+    if candidates.len() > 1 || candidates[0].pages.len() > 1 {
+        page::print_candidates(&candidates);
+    }
+
+    let pick = options.pick.unwrap_or(1);
+    let count = candidates.len();
+    let candidate = match candidates.into_iter().nth(pick.saturating_sub(1)) {
+        Some(candidate) => candidate,
+        None => {
+            error!("Can not pick candidate {}, the page only has {}", pick, count);
+            return Err("Picked candidate does not exist".into());
+        }
+    };
+
+    info!("Picked {} url from {}: {}", page::extension(&candidate.url), candidate.source, candidate.url);
+    if let Some(title) = &candidate.title {
+        info!("Title: {}", title);
+    }
+
+    Ok(candidate)
+}
+
+async fn download_video(url: &Url, headers: &HeaderMap, output: &Path, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<Downloaded, Box<dyn std::error::Error>> {
+    let file_extension = page::extension(url);
+    let downloaded = match file_extension.as_str() {
+        "mp4" | "webm" => {
+            info!("Downloading {} file", file_extension);
+            match video::download_video(url, headers, output, options, client, cancel).await {
+                Ok(downloaded) => downloaded,
                 Err(err) => {
-                    eprintln!("Error downloading file: {}", err);
+                    error!("Error downloading file: {}", err);
                     return Err(err);
                 }
             }
         }
         "m3u8" => {
-            println!("Downloading playlist file");
-            match playlist::download_playlist(url, output, options).await {
-                Ok(_) => {}
+            info!("Downloading playlist file");
+            match playlist::download_playlist(url, headers, output, options, client, cancel).await {
+                Ok(downloaded) => downloaded,
                 Err(err) => {
-                    eprintln!("Error downloading playlist: {}", err);
+                    error!("Error downloading playlist: {}", err);
                     return Err(err);
                 }
             }
         }
+        "mpd" => {
+            error!("DASH manifests are not supported: {}", url);
+            return Err("DASH manifests are not supported".into());
+        }
         _ => {
-            eprintln!("Unsupported file extension: {}", file_extension);
+            error!("Unsupported file extension: {}", file_extension);
             return Err(Box::new(crate::error::extension_error::ExtensionError));
         }
-    }
+    };
 
-    Ok(())
+    Ok(downloaded)
 }
 
-pub async fn download(url: &str, output: &Path, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
-    if std::path::Path::new(output).exists() {
-        eprintln!("File already exists: {}", output.to_string_lossy());
-        return Err("File already exists".into());
+/// the output path of a download without an explicit output, rendered from the output template
+async fn render_output(page_url: &Url, candidate: &Candidate, options: &Options, client: &DownloadClient) -> PathBuf {
+    let mut variant_height = candidate.height;
+    if variant_height.is_none() && page::extension(&candidate.url) == "m3u8" {
+        variant_height = playlist::selected_height(&candidate.url, &candidate.headers, client).await;
     }
 
+    let metadata = Metadata::new(page_url, &candidate.url, candidate.title.clone(), variant_height);
 
-    println!("Downloading {} from: {}", output.to_string_lossy(), url);
+    output::render(&options.output_template, &options.output_dir, &metadata)
+}
+
+/// downloads the best media of `url` to `output`, or to the path rendered from the output
+/// template if it is not set
+pub async fn download(url: &str, output: Option<&Path>, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<Downloaded, Box<dyn std::error::Error>> {
+    if let Some(output) = output.filter(|output| output.exists()) {
+        error!("File already exists: {}", output.to_string_lossy());
+        return Err(Box::new(ExistsError { output: output.to_path_buf() }));
+    }
 
     let parsed_url = match url::Url::parse(url) {
         Ok(url) => url,
         Err(e) => {
-            eprintln!("Error parsing url: {}", e);
+            error!("Error parsing url: {}", e);
             return Err(Box::new(e));
         }
     };
 
-    match download_video(&parsed_url, output, options).await {
-        Ok(_) => {}
-        Err(ref err) if err.is::<crate::error::extension_error::ExtensionError>() => {
-            println!("Trying to find a video or playlist file in page");
-            match find_video_or_playlist(&parsed_url).await {
-                Ok(video_url) => {
-                    match download_video(&video_url, &output, options).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            eprintln!("Error downloading video or playlist: {}", err);
-                            return Err(err);
-                        }
-                    }
-                }
-                Err(err) => {
-                    eprintln!("Error finding video or playlist: {}", err);
-                    return Err(err);
-                }
+    // direct video and playlist urls are handled by their extractor, everything else is searched
+    if extractor::find(&parsed_url).is_none() {
+        info!("Trying to find a video or playlist file in page");
+    }
+
+    let candidate = match find_video_or_playlist(&parsed_url, options, client).await {
+        Ok(candidate) => candidate,
+        Err(err) => {
+            error!("Error finding video or playlist: {}", err);
+            return Err(err);
+        }
+    };
+
+    let output = match output {
+        Some(output) => output.to_path_buf(),
+        None => {
+            let output = render_output(&parsed_url, &candidate, options, client).await;
+
+            if output.exists() {
+                error!("File already exists: {}", output.to_string_lossy());
+                return Err(Box::new(ExistsError { output }));
             }
+
+            output
+        }
+    };
+    let output = output.as_path();
+
+    // the outputs of templates and daemon jobs may be in folders that do not exist yet
+    if let Some(parent) = output.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        if let Err(err) = std::fs::create_dir_all(parent) {
+            error!("Error creating folder ({}): {}", parent.to_string_lossy(), err);
+            return Err(Box::new(err));
         }
+    }
+
+    info!("Downloading {} from: {}", output.to_string_lossy(), url);
+
+    let downloaded = match download_video(&candidate.url, &candidate.headers, output, options, client, cancel).await {
+        Ok(downloaded) => downloaded,
         Err(err) => {
+            error!("Error downloading video or playlist: {}", err);
             return Err(err);
         }
+    };
+
+
+    info!("Finished downloading {} from: {}", output.to_string_lossy(), url);
+
+    // the checksum is of a single file, the parts can not be checked against it
+    if options.checksum.is_some() && !downloaded.parts.is_empty() {
+        return Err("The checksum can not be checked, the playlist was split into parts".into());
     }
 
+    if let Some(checksum) = options.checksum.as_ref().or(downloaded.checksum.as_ref()).filter(|_| downloaded.parts.is_empty()) {
+        if let Err(err) = checksum.verify_file(output) {
+            error!("{}", err);
 
-    println!("Finished downloading {} from: {}", output.to_string_lossy(), url);
+            // the file and the segments it was merged from are moved aside, so the next download
+            // fetches everything again instead of skipping the file or merging the same bad data
+            match set_aside(output) {
+                Ok(mismatch) => error!("Kept the downloaded file as {}", mismatch.to_string_lossy()),
+                Err(err) => error!("Error renaming file: {}", err),
+            }
 
+            let segment_folder = PathBuf::from(output.to_string_lossy().to_string() + "_segments");
+            if segment_folder.exists() {
+                match set_aside(&segment_folder) {
+                    Ok(mismatch) => error!("Kept its segments in {}", mismatch.to_string_lossy()),
+                    Err(err) => error!("Error renaming folder: {}", err),
+                }
+            }
+
+            return Err(err);
+        }
+
+        info!("Checksum {} matches", checksum);
+    }
 
     // now we have the final file, but we should use ffmpeg to convert it to a playable format
-    println!("Converting file to mp4");
+    for output in downloaded.outputs() {
+        convert(output)?;
+    }
+
+    Ok(downloaded)
+}
+
+/// renames `path` to `<path>.mismatch`, replacing what an earlier mismatch left there
+fn set_aside(path: &Path) -> std::io::Result<PathBuf> {
+    let mismatch = PathBuf::from(path.to_string_lossy().to_string() + ".mismatch");
+
+    if mismatch.is_dir() {
+        std::fs::remove_dir_all(&mismatch)?;
+    }
+    std::fs::rename(path, &mismatch)?;
+
+    Ok(mismatch)
+}
+
+/// remuxes `output` with ffmpeg to a playable format, in place
+pub fn convert(output: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Converting file to mp4");
 
     let outfile_name = output.to_str().unwrap();
     let ffmpeg_result = std::process::Command::new("ffmpeg")
-        .args(&[
+        .args([
             "-loglevel",
             "error",
             "-i",
@@ -142,18 +316,18 @@ pub async fn download(url: &str, output: &Path, options: &Options) -> Result<(),
 
 
     if let Err(err) = ffmpeg_result {
-        eprintln!("Error converting file: {}", err);
+        error!("Error converting file: {}", err);
         return Err(Box::new(err));
     }
 
     // remove the original output file and move the ffmpeg output to the original output file
     if let Err(err) = std::fs::remove_file(output) {
-        eprintln!("Error removing file: {}", err);
+        error!("Error removing file: {}", err);
         return Err(Box::new(err));
     }
 
     if let Err(err) = std::fs::rename(outfile_name.to_string() + ".mp4", outfile_name) {
-        eprintln!("Error renaming file: {}", err);
+        error!("Error renaming file: {}", err);
         return Err(Box::new(err));
     }
 
@@ -0,0 +1,71 @@
+use reqwest::{Certificate, ClientBuilder, Identity};
+use url::Url;
+
+use crate::download::host_matches;
+use crate::options::TlsOptions;
+
+/// the certificates and client identity of `tls`, read from their pem files
+pub struct Tls {
+    root_certificates: Vec<Certificate>,
+    identity: Option<Identity>,
+}
+
+impl Tls {
+    pub fn load(tls: &TlsOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut root_certificates = Vec::new();
+
+        for path in &tls.root_certificates {
+            let pem = match std::fs::read(path) {
+                Ok(pem) => pem,
+                Err(err) => return Err(format!("Error reading certificate ({}): {}", path.to_string_lossy(), err).into()),
+            };
+
+            match Certificate::from_pem_bundle(&pem) {
+                Ok(certificates) => root_certificates.extend(certificates),
+                Err(err) => return Err(format!("Error parsing certificate ({}): {}", path.to_string_lossy(), err).into()),
+            }
+        }
+
+        let identity = match (&tls.client_certificate, &tls.client_key) {
+            (Some(certificate), Some(key)) => {
+                let certificate = std::fs::read(certificate)
+                    .map_err(|err| format!("Error reading client certificate ({}): {}", certificate.to_string_lossy(), err))?;
+                let key = std::fs::read(key)
+                    .map_err(|err| format!("Error reading client key ({}): {}", key.to_string_lossy(), err))?;
+
+                match Identity::from_pkcs8_pem(&certificate, &key) {
+                    Ok(identity) => Some(identity),
+                    Err(err) => return Err(format!("Error parsing client certificate, the key must be pkcs8: {}", err).into()),
+                }
+            }
+            (None, None) => None,
+            _ => return Err("A client certificate needs both the certificate and the key".into()),
+        };
+
+        Ok(Self { root_certificates, identity })
+    }
+
+    pub fn apply(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        for certificate in &self.root_certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+        }
+
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+
+        builder
+    }
+}
+
+/// whether the certificate of `url` is not verified
+pub fn is_insecure(insecure_hosts: &[String], url: &Url) -> bool {
+    insecure_hosts.iter().any(|insecure| host_matches(insecure, url))
+}
+
+/// the loud warning for every host whose certificate is not verified
+pub fn insecure_warnings(tls: &TlsOptions) -> Vec<String> {
+    tls.insecure_hosts.iter()
+        .map(|host| format!("WARNING: the TLS certificate of {} is NOT verified, anyone on the network can read and change the download", host))
+        .collect()
+}
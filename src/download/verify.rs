@@ -0,0 +1,610 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use tokio_util::sync::CancellationToken;
+use url::Url;
+
+use crate::download::{playlist, search, DownloadClient};
+use crate::download::playlist::Playlist;
+use crate::download::playlist::segment::{self, format_time, Segment};
+use crate::download::playlist::ts::{pes_timestamps, read_timestamp, Packet, NULL_PID, PTS_WRAP, SYNC_BYTE, TS_PACKET};
+use crate::download::playlist::validate::box_type;
+use crate::options::Options;
+
+/// ticks per second of MPEG-TS timestamps
+const PTS_CLOCK: f64 = 90_000.;
+/// seconds a stream may jump ahead or end early before it counts as a gap
+const MAX_GAP: f64 = 1.;
+
+/// a problem found in a media file
+#[derive(Debug, Clone)]
+pub struct Issue {
+    /// seconds since the start of the stream the problem covers, not known for broken boxes
+    pub time: Option<(f64, f64)>,
+    /// byte offset in the file
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.time {
+            Some((start, end)) if end > start => write!(f, "{} at {} - {} (byte {})", self.reason, format_time(start), format_time(end), self.offset),
+            Some((start, _)) => write!(f, "{} at {} (byte {})", self.reason, format_time(start), self.offset),
+            None => write!(f, "{} (byte {})", self.reason, self.offset),
+        }
+    }
+}
+
+/// the problems of a file and the duration of its longest stream
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub issues: Vec<Issue>,
+    /// seconds from the first to the last timestamp, not known for fragmented MP4
+    pub duration: Option<f64>,
+}
+
+impl Scan {
+    /// adds a gap at the end if the streams are shorter than the `#EXTINF` durations of the playlist
+    pub fn expect_duration(&mut self, total_duration: f64) {
+        if let Some(duration) = self.duration.filter(|duration| total_duration - duration > MAX_GAP) {
+            self.issues.push(Issue {
+                time: Some((duration, total_duration)),
+                offset: 0,
+                reason: format!("Streams end after {:.1}s of {:.1}s", duration, total_duration),
+            });
+        }
+    }
+}
+
+/// scans an MPEG-TS file for continuity counter errors and timestamp gaps, or an MP4 file for
+/// truncated boxes and gaps in the sample durations of its tracks, other formats like packed audio
+/// can not be checked and have no scan
+pub fn scan(path: &Path) -> Result<Option<Scan>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+
+    let mut start = [0; 8];
+    let len = file.read(&mut start)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    match &start[..len] {
+        [SYNC_BYTE, ..] => Ok(Some(scan_ts(BufReader::new(file))?)),
+        start if box_type(start).is_some() => scan_mp4(file).map(Some),
+        _ => {
+            info!("{} is neither MPEG-TS nor MP4, it is not verified", path.to_string_lossy());
+            Ok(None)
+        }
+    }
+}
+
+/// state of a single stream of a transport stream
+#[derive(Default)]
+struct Stream {
+    continuity: Option<u8>,
+    /// last timestamp, counted on over wraps
+    pts: Option<i64>,
+    /// timestamp and stream time after the last jump back, the time goes on from there
+    base: (i64, f64),
+}
+
+impl Stream {
+    /// the seconds since the start of the stream at timestamp `pts`
+    fn time(&self, pts: i64) -> f64 {
+        self.base.1 + (pts - self.base.0) as f64 / PTS_CLOCK
+    }
+
+    /// `pts` counted on from the last timestamp, so a wrap of the 33 bits is no jump
+    fn unwrap(&self, pts: i64) -> i64 {
+        let Some(last) = self.pts else {
+            return pts;
+        };
+
+        let pts = pts + last - last.rem_euclid(PTS_WRAP);
+        match pts - last {
+            diff if diff < -PTS_WRAP / 2 => pts + PTS_WRAP,
+            diff if diff > PTS_WRAP / 2 => pts - PTS_WRAP,
+            _ => pts,
+        }
+    }
+}
+
+/// reads a full packet, returns how many bytes were read if the file ends before
+fn read_packet(reader: &mut impl Read, packet: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+
+    while read < packet.len() {
+        match reader.read(&mut packet[read..])? {
+            0 => break,
+            len => read += len,
+        }
+    }
+
+    Ok(read)
+}
+
+/// the presentation timestamp of a packet that starts the PES of an audio or video stream
+fn pes_timestamp(packet: &[u8], payload: usize) -> Option<i64> {
+    if !(0xC0..=0xEF).contains(packet.get(payload + 3)?) {
+        return None;
+    }
+
+    pes_timestamps(packet, payload).first().map(|start| read_timestamp(&packet[*start..]))
+}
+
+/// the streams of a transport stream while it is scanned packet by packet
+#[derive(Default)]
+struct TransportStream {
+    scan: Scan,
+    streams: HashMap<u16, Stream>,
+}
+
+impl TransportStream {
+    fn packet(&mut self, packet: &[u8], offset: u64) {
+        let Some(Packet { pid, payload_start, counter, has_payload, discontinuity, payload, .. }) = Packet::parse(packet) else {
+            return;
+        };
+        if pid == NULL_PID {
+            return;
+        }
+
+        let stream = self.streams.entry(pid).or_default();
+        let now = stream.pts.map(|pts| stream.time(pts));
+
+        // the counter only goes up with a payload, a repeated packet keeps it
+        if has_payload {
+            if let Some(last) = stream.continuity.filter(|last| !discontinuity && counter != *last && counter != (*last + 1) & 0x0F) {
+                self.scan.issues.push(Issue {
+                    time: now.map(|now| (now, now)),
+                    offset,
+                    reason: format!("Continuity error on pid {}, {} follows {}", pid, counter, last),
+                });
+            }
+            stream.continuity = Some(counter);
+        }
+
+        let pts = match has_payload && payload_start {
+            true => pes_timestamp(packet, payload),
+            false => None,
+        };
+        let Some(pts) = pts.map(|pts| stream.unwrap(pts)) else {
+            return;
+        };
+
+        match (stream.pts, now) {
+            (Some(last), Some(now)) => {
+                let jump = (pts - last) as f64 / PTS_CLOCK;
+
+                if discontinuity || jump < -MAX_GAP {
+                    // the timestamps start over, the stream time goes on from the last one
+                    if !discontinuity {
+                        self.scan.issues.push(Issue {
+                            time: Some((now, now)),
+                            offset,
+                            reason: format!("Timestamps of pid {} jump back by {:.1}s", pid, -jump),
+                        });
+                    }
+                    stream.base = (pts, now);
+                } else if jump > MAX_GAP {
+                    self.scan.issues.push(Issue {
+                        time: Some((now, now + jump)),
+                        offset,
+                        reason: format!("Timestamps of pid {} jump ahead by {:.1}s", pid, jump),
+                    });
+                }
+            }
+            _ => stream.base = (pts, 0.),
+        }
+
+        stream.pts = Some(pts);
+        let time = stream.time(pts);
+        self.scan.duration = Some(self.scan.duration.map_or(time, |duration| duration.max(time)));
+    }
+}
+
+fn scan_ts(mut reader: impl Read) -> std::io::Result<Scan> {
+    let mut transport_stream = TransportStream::default();
+    let mut packet = [0; TS_PACKET];
+    let mut len = read_packet(&mut reader, &mut packet)?;
+    let mut offset = 0;
+    let mut in_sync = true;
+
+    while len == TS_PACKET {
+        if packet[0] != SYNC_BYTE {
+            if in_sync {
+                transport_stream.scan.issues.push(Issue { time: None, offset, reason: "Lost the sync byte".to_string() });
+                in_sync = false;
+            }
+
+            // the packet starts over at the next sync byte
+            let skip = packet[1..].iter().position(|byte| *byte == SYNC_BYTE).map_or(TS_PACKET, |position| position + 1);
+            packet.copy_within(skip.., 0);
+            len = TS_PACKET - skip + read_packet(&mut reader, &mut packet[TS_PACKET - skip..])?;
+            offset += skip as u64;
+            continue;
+        }
+
+        in_sync = true;
+        transport_stream.packet(&packet, offset);
+
+        offset += TS_PACKET as u64;
+        len = read_packet(&mut reader, &mut packet)?;
+    }
+
+    if len > 0 && packet[0] == SYNC_BYTE {
+        transport_stream.scan.issues.push(Issue { time: None, offset, reason: format!("Truncated packet of {} bytes", len) });
+    }
+
+    Ok(transport_stream.scan)
+}
+
+/// the child boxes of `data` as type and content, up to the first broken one
+fn boxes(mut data: &[u8]) -> Vec<(&str, &[u8])> {
+    let mut boxes = Vec::new();
+
+    while let Some(box_type) = box_type(data) {
+        let (size, header) = match u32::from_be_bytes(data[0..4].try_into().unwrap()) {
+            0 => (data.len(), 8),
+            1 => match data.get(8..16) {
+                Some(size) => (u64::from_be_bytes(size.try_into().unwrap()) as usize, 16),
+                None => break,
+            },
+            size => (size as usize, 8),
+        };
+
+        if size < header || size > data.len() {
+            break;
+        }
+
+        boxes.push((box_type, &data[header..size]));
+        data = &data[size..];
+    }
+
+    boxes
+}
+
+/// the content of the first child box of `data` with type `name`
+fn child<'a>(data: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    boxes(data).into_iter().find_map(|(box_type, content)| (box_type == name).then_some(content))
+}
+
+/// gaps in the sample durations of the audio and video tracks of a `moov` box, returns the
+/// duration of the longest track
+fn scan_tracks(moov: &[u8], offset: u64, issues: &mut Vec<Issue>) -> Option<f64> {
+    let mut duration = None;
+
+    for (_, trak) in boxes(moov).into_iter().filter(|(box_type, _)| *box_type == "trak") {
+        let Some(mdia) = child(trak, "mdia") else {
+            continue;
+        };
+
+        let kind = match child(mdia, "hdlr").and_then(|hdlr| hdlr.get(8..12)) {
+            Some(b"vide") => "video",
+            Some(b"soun") => "audio",
+            _ => continue,
+        };
+
+        let timescale = child(mdia, "mdhd").and_then(|mdhd| match mdhd.first()? {
+            1 => mdhd.get(20..24),
+            _ => mdhd.get(12..16),
+        });
+        let Some(timescale) = timescale.map(|timescale| u32::from_be_bytes(timescale.try_into().unwrap())).filter(|timescale| *timescale > 0) else {
+            continue;
+        };
+        let timescale = timescale as f64;
+
+        let Some(stts) = child(mdia, "minf").and_then(|minf| child(minf, "stbl")).and_then(|stbl| child(stbl, "stts")) else {
+            continue;
+        };
+
+        let mut time = 0.;
+        for entry in stts.get(8..).unwrap_or_default().chunks_exact(8) {
+            let count = u32::from_be_bytes(entry[0..4].try_into().unwrap()) as f64;
+            let delta = u32::from_be_bytes(entry[4..8].try_into().unwrap()) as f64 / timescale;
+
+            // a sample that lasts longer than a gap covers content that is missing
+            if delta > MAX_GAP {
+                issues.push(Issue {
+                    time: Some((time, time + count * delta)),
+                    offset,
+                    reason: format!("{} {} samples last {:.1}s", count, kind, delta),
+                });
+            }
+
+            time += count * delta;
+        }
+
+        duration = Some(duration.map_or(time, |duration: f64| duration.max(time)));
+    }
+
+    duration
+}
+
+fn scan_mp4(mut file: File) -> Result<Scan, Box<dyn std::error::Error>> {
+    let mut scan = Scan::default();
+    let len = file.metadata()?.len();
+    let mut offset = 0;
+
+    while offset < len {
+        let mut header = [0; 16];
+        let read = read_packet(&mut file, &mut header[..8])?;
+
+        let Some(name) = box_type(&header[..read]).map(str::to_string) else {
+            scan.issues.push(Issue { time: None, offset, reason: "No box".to_string() });
+            break;
+        };
+
+        let (size, header_len) = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+            0 => (len - offset, 8),
+            1 => {
+                read_packet(&mut file, &mut header[8..16])?;
+                (u64::from_be_bytes(header[8..16].try_into().unwrap()), 16)
+            }
+            size => (size as u64, 8),
+        };
+
+        if size < header_len {
+            scan.issues.push(Issue { time: None, offset, reason: format!("{} box has size {}", name, size) });
+            break;
+        }
+        if offset + size > len {
+            scan.issues.push(Issue { time: None, offset, reason: format!("Truncated {} box, {} of {} bytes", name, len - offset, size) });
+            break;
+        }
+
+        if name == "moov" {
+            let mut moov = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut moov)?;
+            scan.duration = scan_tracks(&moov, offset, &mut scan.issues);
+        }
+
+        offset += size;
+        file.seek(SeekFrom::Start(offset))?;
+    }
+
+    Ok(scan)
+}
+
+/// the indices of the segments the issues are in, by the `#EXTINF` durations or for issues
+/// without a time by the `sizes` of the segment files
+pub fn affected_segments(issues: &[Issue], segments: &[Segment], sizes: Option<&[u64]>) -> Vec<usize> {
+    let mut affected = BTreeSet::new();
+
+    for issue in issues {
+        match issue.time {
+            Some((start, end)) => {
+                let mut segment_start = 0.;
+
+                for (index, segment) in segments.iter().enumerate() {
+                    let segment_end = segment_start + segment.duration;
+                    let overlap = end.min(segment_end) - start.max(segment_start);
+
+                    // a gap that only touches a segment by a frame does not count for it
+                    let is_in = match end > start {
+                        true => overlap > segment.duration.min(MAX_GAP) / 2.,
+                        false => start >= segment_start && (start < segment_end || index + 1 == segments.len()),
+                    };
+                    if is_in {
+                        affected.insert(index);
+                    }
+
+                    segment_start = segment_end;
+                }
+            }
+            None => {
+                let Some(sizes) = sizes else {
+                    continue;
+                };
+
+                let mut segment_start = 0;
+                for (index, size) in sizes.iter().enumerate() {
+                    if issue.offset < segment_start + size {
+                        affected.insert(index);
+                        break;
+                    }
+                    segment_start += size;
+                }
+            }
+        }
+    }
+
+    affected.into_iter().collect()
+}
+
+/// the problems of a merged playlist, see `check`
+pub struct Problems {
+    pub issues: Vec<Issue>,
+    /// indices of the segments the issues are in
+    pub segments: Vec<usize>,
+}
+
+/// fails if `problems` of `output` are left after its segments were downloaded again
+pub fn ensure_fixed(problems: &Problems, output: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match problems.issues.len() {
+        0 => Ok(()),
+        count => Err(format!("{} problems are left in {} after downloading the segments again", count, output.to_string_lossy()).into()),
+    }
+}
+
+/// scans the merged `output` of `playlist` and reports the problems and the segments to download again
+pub fn check(playlist: &Playlist, segment_folder: &Path, output: &Path) -> Result<Problems, Box<dyn std::error::Error>> {
+    let mut scan = match scan(output) {
+        Ok(Some(scan)) => scan,
+        Ok(None) => return Ok(Problems { issues: vec![], segments: vec![] }),
+        Err(err) => {
+            error!("Error verifying {}: {}", output.to_string_lossy(), err);
+            return Err(err);
+        }
+    };
+    scan.expect_duration(playlist.total_duration);
+
+    // the files are only there if the segments were not removed
+    let sizes = playlist.segments.iter()
+        .map(|segment| std::fs::metadata(segment_folder.join(&segment.name)).map(|metadata| metadata.len()))
+        .collect::<Result<Vec<_>, _>>()
+        .ok();
+
+    let segments = affected_segments(&scan.issues, &playlist.segments, sizes.as_deref());
+
+    if scan.issues.is_empty() {
+        info!("Verified {}: no continuity errors, timestamp gaps or truncated boxes", output.to_string_lossy());
+    }
+    for issue in &scan.issues {
+        error!("{}", issue);
+    }
+    for index in &segments {
+        let segment = &playlist.segments[*index];
+        error!("Segment {} should be downloaded again: {}", segment.name, segment.uri);
+    }
+    if !scan.issues.is_empty() && segments.is_empty() {
+        error!("Can not tell which segments the problems are in");
+    }
+
+    Ok(Problems { issues: scan.issues, segments })
+}
+
+/// downloads the `segments` of `playlist` again and merges them to `output` once more, segments
+/// that are not in `segment_folder` are downloaded as well
+pub async fn refetch(playlist: &Playlist, segments: &[usize], segment_folder: &Path, output: &Path, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Downloading {} segments again", segments.len());
+
+    if let Err(err) = std::fs::create_dir_all(segment_folder) {
+        error!("Error creating folder: {}", err);
+        return Err(Box::new(err));
+    }
+
+    for index in segments {
+        let path = segment_folder.join(&playlist.segments[*index].name);
+        if let Err(err) = std::fs::remove_file(&path).or_else(|err| match err.kind() {
+            std::io::ErrorKind::NotFound => Ok(()),
+            _ => Err(err),
+        }) {
+            error!("Error removing file ({}): {}", path.to_string_lossy(), err);
+            return Err(Box::new(err));
+        }
+    }
+
+    segment::download_segments(playlist, segment_folder, client, options, cancel).await?;
+    playlist::merge(playlist, segment_folder, output, options.fsync)
+}
+
+/// checks a downloaded `file`, with the playlist it was downloaded from the problems are mapped
+/// to its segments and with `refetch` these are downloaded again, returns whether no problems are left
+pub async fn verify_file(file: &Path, playlist_url: Option<&Url>, refetch: bool, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<bool, Box<dyn std::error::Error>> {
+    let Some(playlist_url) = playlist_url else {
+        let Some(scan) = scan(file)? else {
+            return Ok(true);
+        };
+
+        if scan.issues.is_empty() {
+            info!("Verified {}: no continuity errors, timestamp gaps or truncated boxes", file.to_string_lossy());
+        }
+        for issue in &scan.issues {
+            error!("{}", issue);
+        }
+
+        return Ok(scan.issues.is_empty());
+    };
+
+    let playlist = playlist::parse_playlist(playlist_url, client).await?;
+    let segment_folder = PathBuf::from(file.to_string_lossy().to_string() + "_segments");
+
+    let problems = check(&playlist, &segment_folder, file)?;
+    if !refetch || problems.segments.is_empty() {
+        return Ok(problems.issues.is_empty());
+    }
+
+    self::refetch(&playlist, &problems.segments, &segment_folder, file, options, client, cancel).await?;
+    let problems = check(&playlist, &segment_folder, file)?;
+
+    // the merged segments replace the converted file, so they are converted again
+    search::convert(file)?;
+
+    ensure_fixed(&problems, file)?;
+
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::playlist::ts::tests::packet;
+
+    fn segments(durations: &[f64]) -> Vec<Segment> {
+        durations.iter().enumerate().map(|(index, duration)| Segment {
+            name: format!("seg{}.ts", index),
+            uri: Url::parse(&format!("http://example.com/seg{}.ts", index)).unwrap(),
+            duration: *duration,
+            downloaded: true,
+            discontinuity: false,
+        }).collect()
+    }
+
+    fn issue(time: Option<(f64, f64)>, offset: u64) -> Issue {
+        Issue { time, offset, reason: String::new() }
+    }
+
+    #[test]
+    fn unwrap_counts_on_over_a_wrap() {
+        let stream = Stream { continuity: None, pts: Some(PTS_WRAP - 3000), base: (PTS_WRAP - 3000, 10.) };
+
+        let pts = stream.unwrap(1500);
+        assert_eq!(pts, PTS_WRAP + 1500);
+        assert!((stream.time(pts) - 10.05).abs() < 1e-9);
+
+        // and back again for a timestamp from before the wrap
+        let stream = Stream { pts: Some(PTS_WRAP + 1500), ..stream };
+        assert_eq!(stream.unwrap(PTS_WRAP - 3000), PTS_WRAP - 3000);
+    }
+
+    #[test]
+    fn unwrap_keeps_the_first_timestamp() {
+        assert_eq!(Stream::default().unwrap(1234), 1234);
+    }
+
+    #[test]
+    fn scan_ts_finds_continuity_errors() {
+        let bytes = [packet(256, 0, Some(0), None), packet(256, 1, None, None), packet(256, 1, None, None), packet(256, 5, Some(3000), None)].concat();
+
+        let scan = scan_ts(&bytes[..]).unwrap();
+        assert_eq!(scan.issues.len(), 1);
+        assert_eq!(scan.issues[0].offset, 3 * TS_PACKET as u64);
+        assert_eq!(scan.issues[0].reason, "Continuity error on pid 256, 5 follows 1");
+    }
+
+    #[test]
+    fn scan_ts_finds_gaps_but_not_wraps() {
+        let bytes = [packet(256, 0, Some(PTS_WRAP - 3000), None), packet(256, 1, Some(1500), None), packet(256, 2, Some(1500 + 3 * 90_000), None)].concat();
+
+        let scan = scan_ts(&bytes[..]).unwrap();
+        assert_eq!(scan.issues.len(), 1);
+        assert_eq!(scan.issues[0].reason, "Timestamps of pid 256 jump ahead by 3.0s");
+        let (start, end) = scan.issues[0].time.unwrap();
+        assert!((start - 0.05).abs() < 1e-9 && (end - 3.05).abs() < 1e-9);
+        assert!((scan.duration.unwrap() - 3.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn affected_segments_by_time() {
+        let segments = segments(&[4., 4., 4.]);
+
+        // a point in time belongs to the segment it is in, the end to the last one
+        assert_eq!(affected_segments(&[issue(Some((5., 5.)), 0)], &segments, None), vec![1]);
+        assert_eq!(affected_segments(&[issue(Some((12., 12.)), 0)], &segments, None), vec![2]);
+
+        // a gap counts for the segments it covers, not the ones it only touches
+        assert_eq!(affected_segments(&[issue(Some((3.9, 8.1)), 0)], &segments, None), vec![1]);
+        assert_eq!(affected_segments(&[issue(Some((3., 9.)), 0)], &segments, None), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn affected_segments_by_offset() {
+        let segments = segments(&[4., 4., 4.]);
+        let issues = [issue(None, 150), issue(None, 100), issue(None, 250)];
+
+        assert_eq!(affected_segments(&issues, &segments, Some(&[100, 100, 100])), vec![1, 2]);
+        assert_eq!(affected_segments(&issues, &segments, None), Vec::<usize>::new());
+        assert_eq!(affected_segments(&[issue(None, 400)], &segments, Some(&[100, 100, 100])), Vec::<usize>::new());
+    }
+}
@@ -0,0 +1,232 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, ETAG};
+use url::Url;
+
+use crate::download::{self, checksum::Checksum, DownloadClient};
+use crate::error::mismatch_error::MismatchError;
+
+/// weight of the newest range in the speed of a mirror
+const SPEED_SMOOTHING: f64 = 0.3;
+
+#[derive(Default)]
+struct MirrorState {
+    /// bytes per second of the finished ranges, not known until the first one finished
+    speed: Option<f64>,
+    /// ranges being downloaded from the mirror
+    active: usize,
+    dropped: bool,
+}
+
+/// a range running on a mirror, it stops counting as active when it is dropped, so a range that
+/// returns early does not keep the mirror busy
+pub struct MirrorSlot<'a> {
+    mirrors: &'a Mirrors,
+    index: usize,
+    active: bool,
+}
+
+impl MirrorSlot<'_> {
+    pub fn url(&self) -> &Url {
+        &self.mirrors.urls[self.index]
+    }
+
+    /// the index of the mirror, it is no longer counted as active by the slot
+    fn into_index(mut self) -> usize {
+        self.active = false;
+        self.index
+    }
+}
+
+impl Drop for MirrorSlot<'_> {
+    fn drop(&mut self) {
+        if self.active {
+            self.mirrors.states.lock().unwrap()[self.index].active -= 1;
+        }
+    }
+}
+
+/// the urls a video is downloaded from, the first one and the mirrors that have the same content
+pub struct Mirrors {
+    urls: Vec<Url>,
+    /// the states of all urls under one lock, so picking and dropping see the same ones
+    states: Mutex<Vec<MirrorState>>,
+    size: u64,
+    etag: Option<String>,
+    /// checksum of the whole file the first url sent
+    checksum: Option<Checksum>,
+}
+
+fn etag(headers: &HeaderMap) -> Option<String> {
+    headers.get(ETAG).and_then(|etag| etag.to_str().ok()).map(|etag| etag.trim_start_matches("W/").to_string())
+}
+
+impl Mirrors {
+    /// `url` and the `mirrors` with the same size and etag as it, the others are skipped
+    pub async fn verify(client: &DownloadClient, url: &Url, mirrors: &[Url]) -> Result<Self, Box<dyn std::error::Error>> {
+        let headers = client.head(url).await?;
+        let size = download::content_length(&headers)?;
+
+        let mut verified = Self { urls: vec![], states: Mutex::new(vec![]), size, etag: etag(&headers), checksum: Checksum::from_headers(&headers) };
+        verified.push(url.clone());
+
+        for mirror in mirrors {
+            let headers = match client.head(mirror).await {
+                Ok(headers) => headers,
+                Err(err) => {
+                    error!("Skipping mirror {}: {}", mirror, err);
+                    continue;
+                }
+            };
+
+            match verified.check(mirror, download::content_length(&headers).ok(), etag(&headers).as_deref()) {
+                Ok(_) => verified.push(mirror.clone()),
+                Err(err) => error!("{}, skipping it", err),
+            }
+        }
+
+        if verified.urls.len() > 1 {
+            info!("Downloading from {} mirrors", verified.urls.len());
+        }
+
+        Ok(verified)
+    }
+
+    fn push(&mut self, url: Url) {
+        self.urls.push(url);
+        self.states.get_mut().unwrap().push(MirrorState::default());
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn checksum(&self) -> Option<Checksum> {
+        self.checksum.clone()
+    }
+
+    /// whether a response of the mirror has the size and the etag of the first url, servers
+    /// that do not send an etag are only compared by size
+    pub fn check(&self, url: &Url, size: Option<u64>, etag: Option<&str>) -> Result<(), MismatchError> {
+        if let Some(size) = size.filter(|size| *size != self.size) {
+            return Err(MismatchError { url: url.clone(), reason: format!("{} bytes instead of {}", size, self.size) });
+        }
+
+        if let (Some(etag), Some(expected)) = (etag.map(|etag| etag.trim_start_matches("W/")), &self.etag) {
+            if etag != expected {
+                return Err(MismatchError { url: url.clone(), reason: format!("etag {} instead of {}", etag, expected) });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the mirror the next range is downloaded from: one that was not measured yet, otherwise the
+    /// one with the most speed per running range, so the ranges are spread by speed
+    pub fn pick(&self) -> MirrorSlot<'_> {
+        let mut states = self.states.lock().unwrap();
+
+        let (index, _) = states.iter().enumerate()
+            .filter(|(_, state)| !state.dropped)
+            .map(|(index, state)| (index, state.speed.unwrap_or(f64::MAX) / (state.active + 1) as f64))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            // the last mirror is never dropped
+            .unwrap_or_default();
+
+        states[index].active += 1;
+
+        MirrorSlot { mirrors: self, index, active: true }
+    }
+
+    /// a range of `bytes` from the mirror of `slot` finished in `elapsed`
+    pub fn finished(&self, slot: MirrorSlot, bytes: u64, elapsed: Duration) {
+        let index = slot.into_index();
+        let mut states = self.states.lock().unwrap();
+        let state = &mut states[index];
+        state.active -= 1;
+
+        if bytes > 0 && !elapsed.is_zero() {
+            let speed = bytes as f64 / elapsed.as_secs_f64();
+            state.speed = Some(state.speed.map_or(speed, |old| old * (1. - SPEED_SMOOTHING) + speed * SPEED_SMOOTHING));
+        }
+    }
+
+    /// a range of the mirror of `slot` failed, the mirror is dropped unless it is the last one,
+    /// returns whether it was dropped
+    pub fn failed(&self, slot: MirrorSlot, err: &(dyn std::error::Error + Send + 'static)) -> bool {
+        let index = slot.into_index();
+        // counted under the same lock the mirror is dropped with, so two failing ranges can not drop the last two
+        let mut states = self.states.lock().unwrap();
+        let remaining = states.iter().filter(|state| !state.dropped).count();
+
+        let state = &mut states[index];
+        state.active -= 1;
+
+        if state.dropped || remaining <= 1 {
+            return false;
+        }
+
+        state.dropped = true;
+        error!("Dropping mirror {} ({} left): {}", self.urls[index], remaining - 1, err);
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirrors(count: usize) -> Mirrors {
+        let mut mirrors = Mirrors { urls: vec![], states: Mutex::new(vec![]), size: 100, etag: None, checksum: None };
+        for i in 0..count {
+            mirrors.push(Url::parse(&format!("https://mirror{}.example.com/video.mp4", i)).unwrap());
+        }
+        mirrors
+    }
+
+    fn error() -> std::io::Error {
+        std::io::Error::other("connection reset")
+    }
+
+    #[test]
+    fn failed_keeps_the_last_mirror() {
+        let mirrors = mirrors(2);
+        let (first, second) = (mirrors.pick(), mirrors.pick());
+        assert_ne!(first.url(), second.url());
+        let left = second.url().clone();
+
+        assert!(mirrors.failed(first, &error()));
+        assert!(!mirrors.failed(second, &error()));
+
+        // the ranges go to the one that is left
+        assert_eq!(mirrors.pick().url(), &left);
+    }
+
+    #[test]
+    fn failing_at_once_keeps_a_mirror() {
+        for _ in 0..100 {
+            let mirrors = mirrors(2);
+            let slots = [mirrors.pick(), mirrors.pick()];
+
+            std::thread::scope(|scope| {
+                for slot in slots {
+                    scope.spawn(|| mirrors.failed(slot, &error()));
+                }
+            });
+
+            assert_eq!(mirrors.states.lock().unwrap().iter().filter(|state| !state.dropped).count(), 1);
+        }
+    }
+
+    #[test]
+    fn dropped_slots_are_no_longer_active() {
+        let mirrors = mirrors(1);
+        let slot = mirrors.pick();
+        assert_eq!(mirrors.states.lock().unwrap()[0].active, 1);
+
+        drop(slot);
+        assert_eq!(mirrors.states.lock().unwrap()[0].active, 0);
+    }
+}
@@ -1,14 +1,18 @@
+pub mod mirror;
 pub mod range;
 
 use std::path::Path;
+use reqwest::header::HeaderMap;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
+use crate::download::{DownloadClient, Downloaded};
 use crate::options::Options;
 
 use range::{SegmentedVideo, Video};
 
-pub async fn download_video(url: &Url, output: &Path, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
-    let video = Video::new(url.clone(), output.to_string_lossy().to_string()).await?;
+pub async fn download_video(url: &Url, headers: &HeaderMap, output: &Path, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<Downloaded, Box<dyn std::error::Error>> {
+    let video = Video::new(url.clone(), output.to_string_lossy().to_string(), client.with_headers(headers), &options.mirrors).await?;
 
     let folder = output.parent()
           .unwrap().join(
@@ -20,9 +24,11 @@ pub async fn download_video(url: &Url, output: &Path, options: &Options) -> Resu
 
     let mut video_segments = SegmentedVideo::new(video, options.block_size, folder);
 
-    video_segments.download(options).await?;
+    video_segments.download(options, cancel).await?;
 
-    video_segments.combine()?;
+    video_segments.combine(options)?;
 
-    Ok(())
+    let checksum = video_segments.checksum();
+
+    Ok(Downloaded { output: output.to_path_buf(), parts: vec![], media_url: url.clone(), variant: None, checksum })
 }
@@ -1,30 +1,40 @@
-use std::{io::Cursor, path::PathBuf, sync::Arc};
-
-use reqwest::header::{HeaderMap, RANGE};
-use tokio::sync::Mutex;
+use std::collections::VecDeque;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use reqwest::header::{HeaderMap, CONTENT_RANGE, ETAG, RANGE};
+use reqwest::StatusCode;
+use tokio_util::sync::CancellationToken;
 use url::Url;
 
-use crate::{download::DownloadClient, options::Options};
+use crate::{download::{concurrency::{self, Concurrency}, part_file::PartFile, DownloadClient}, error::cancelled_error::CancelledError, options::Options, report};
+
+use super::mirror::Mirrors;
+use crate::download::checksum::Checksum;
 
+/// ranges with less remaining bytes are not split for an idle connection
+const MIN_SPLIT: u64 = 512 * 1024;
 
 pub struct Video {
     download_client: DownloadClient,
-    url: Url,
     title: String,
     size: u64,
+    /// the url of the video and the mirrors the ranges are spread over
+    mirrors: Mirrors,
 }
 
 impl Video {
-    pub async fn new(url: Url, title: String) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = DownloadClient::new();
+    pub async fn new(url: Url, title: String, client: DownloadClient, mirrors: &[Url]) -> Result<Self, Box<dyn std::error::Error>> {
 
-        let size = client.get_content_length(&url).await?;
+        let mirrors = Mirrors::verify(&client, &url, mirrors).await?;
 
-        let video = Self { 
+        let video = Self {
             download_client: client,
-            url, 
             title,
-            size
+            size: mirrors.size(),
+            mirrors,
         };
 
         Ok(video)
@@ -32,166 +42,510 @@ impl Video {
 
 }
 
-#[derive(Clone)]
-pub struct VideoSegment {
-    video: Arc<Video>,
-    id: u64,
-    start: u64,
+struct Progress {
+    /// the next byte to download
+    position: u64,
+    /// the last byte of the range, lowered when another connection takes over the rest
     end: u64,
 }
 
-impl VideoSegment {
-    pub fn new(id: u64, video: Arc<Video>, start: u64, end: u64) -> Self {
-        Self { id, video, start, end }
+/// bytes of the video that one connection downloads, written to `<start>.range` in the folder
+struct VideoRange {
+    start: u64,
+    /// failed attempts so far
+    tries: usize,
+    progress: Mutex<Progress>,
+}
+
+impl VideoRange {
+    fn new(start: u64, end: u64, tries: usize) -> Arc<Self> {
+        Arc::new(Self { start, tries, progress: Mutex::new(Progress { position: start, end }) })
+    }
+
+    fn remaining(&self) -> u64 {
+        let progress = self.progress.lock().unwrap();
+        (progress.end + 1).saturating_sub(progress.position)
+    }
+
+    /// bytes that were written to the range file
+    fn saved(&self) -> u64 {
+        self.progress.lock().unwrap().position - self.start
     }
 
-    pub fn size(&self) -> u64 {
-        self.end - self.start
+    /// the bytes that are still missing, after a failed attempt
+    fn rest(&self) -> Option<(u64, u64)> {
+        let progress = self.progress.lock().unwrap();
+        (progress.position <= progress.end).then_some((progress.position, progress.end))
     }
-    
-    pub async fn download(&self, folder: Arc<PathBuf>) -> Result<(), Box<dyn std::error::Error + Send>> {
-        let seg_path = folder.join(format!("{}.ts", self.id));
 
-        if seg_path.exists() {
-            return Ok(());
+    /// hands the second half of the remaining bytes to another connection, if they are worth it
+    fn split(&self) -> Option<(u64, u64)> {
+        let mut progress = self.progress.lock().unwrap();
+        let remaining = (progress.end + 1).saturating_sub(progress.position);
+
+        if remaining < 2 * MIN_SPLIT {
+            return None;
         }
 
+        let middle = progress.position + remaining / 2;
+        let end = progress.end;
+        progress.end = middle - 1;
+
+        Some((middle, end))
+    }
+
+    /// writes the part of `chunk` that still belongs to the range and returns whether it is complete
+    fn write(&self, file: &mut PartFile, chunk: &[u8]) -> std::io::Result<bool> {
+        // locked while writing, so a split can not move the end below what is written
+        let mut progress = self.progress.lock().unwrap();
+        let keep = (chunk.len() as u64).min((progress.end + 1).saturating_sub(progress.position)) as usize;
+
+        file.write_all(&chunk[..keep])?;
+        progress.position += keep as u64;
+
+        Ok(progress.position > progress.end)
+    }
+
+    /// downloads the range from `url`, what arrived before an error or a cancel is kept in the range file
+    async fn download(&self, video: &Video, url: &Url, folder: &Path, cancel: &CancellationToken, fsync: bool) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let mut file = match PartFile::create(&folder.join(format!("{}.range", self.start))) {
+            Ok(file) => file,
+            Err(err) => return Err(Box::new(err)),
+        };
+
+        let result = self.receive(video, url, &mut file, cancel).await;
+
+        let saved = self.saved();
+        if saved > 0 {
+            if let Err(err) = file.finish(Some(saved), fsync) {
+                return Err(Box::new(err));
+            }
+        }
+
+        result
+    }
+
+    async fn receive(&self, video: &Video, url: &Url, file: &mut PartFile, cancel: &CancellationToken) -> Result<(), Box<dyn std::error::Error + Send>> {
+        let client = &video.download_client;
+
         let mut headers = HeaderMap::new();
-        headers.insert(RANGE, 
-            match format!("bytes={}-{}", self.start, self.end).try_into() {
+        headers.insert(RANGE,
+            match format!("bytes={}-{}", self.start, self.progress.lock().unwrap().end).try_into() {
                 Ok(r) => r,
                 Err(e) => return Err(Box::new(e)),
             });
 
-        let response = self.video.download_client.download_header(&self.video.url, &headers).await?;
+        let mut response = tokio::select! {
+            _ = cancel.cancelled() => return Err(Box::new(CancelledError)),
+            response = client.get_header(url, &headers) => response?,
+        };
+
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(Box::new(std::io::Error::other(format!("Server ignored the range request for {}", url))));
+        }
+
+        // a mirror could have changed since it was verified
+        let total = response.headers().get(CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, total)| total.parse::<u64>().ok());
+        let etag = response.headers().get(ETAG).and_then(|etag| etag.to_str().ok());
+
+        if let Err(err) = video.mirrors.check(url, total, etag) {
+            return Err(Box::new(err));
+        }
+
+        loop {
+            let chunk = tokio::select! {
+                _ = cancel.cancelled() => return Err(Box::new(CancelledError)),
+                chunk = client.chunk(url, &mut response) => chunk?,
+            };
+
+            let Some(chunk) = chunk else {
+                break;
+            };
+
+            match self.write(file, &chunk) {
+                // the response is dropped, if the range was split it still sends the bytes of the other half
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(err) => return Err(Box::new(err)),
+            }
+        }
+
+        Err(Box::new(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Incomplete response body")))
+    }
+}
+
+/// the ranges waiting for a connection and the ones being downloaded
+struct Work {
+    pending: VecDeque<(u64, u64, usize)>,
+    in_flight: Vec<Arc<VideoRange>>,
+}
 
-        let mut content = Cursor::new(response);
-        let mut file = match std::fs::File::create(seg_path) {
-            Ok(f) => f,
-            Err(e) => return Err(Box::new(e)),
+impl Work {
+    /// the next pending range, or the second half of the largest range in flight once none are pending
+    fn next(&mut self) -> Option<Arc<VideoRange>> {
+        let range = match self.pending.pop_front() {
+            Some((start, end, tries)) => VideoRange::new(start, end, tries),
+            None => {
+                let (start, end) = self.in_flight.iter().max_by_key(|range| range.remaining())?.split()?;
+                VideoRange::new(start, end, 0)
+            }
         };
 
-        std::io::copy(&mut content, &mut file).unwrap();
+        self.in_flight.push(Arc::clone(&range));
 
-        Ok(())
+        Some(range)
+    }
+
+    fn done(&mut self, range: &Arc<VideoRange>) {
+        self.in_flight.retain(|in_flight| !Arc::ptr_eq(in_flight, range));
     }
 }
 
 
 pub struct SegmentedVideo {
     video: Arc<Video>,
-    segments: Vec<VideoSegment>,
-    total_segments: u64,
+    block_size: u64,
     folder: PathBuf,
 }
 
 impl SegmentedVideo {
     pub fn new(video: Video, block_size: u64, folder: PathBuf) -> Self {
-        let mut segments = vec![];
-
-        let mut start = 0;
-        let mut end = block_size;
-
-        let video = Arc::new(video);
-
-        while end < video.size {
-            segments.push(VideoSegment::new(segments.len() as u64, Arc::clone(&video), start, end));
-            start = end + 1;
-            end = start + block_size;
-        }
-
-        segments.push(VideoSegment::new(segments.len() as u64, Arc::clone(&video), start, video.size));
-
-        let total_segments = segments.len() as u64;
+        Self { video: Arc::new(video), block_size: block_size.max(1), folder }
+    }
 
-        Self { video, segments, folder, total_segments }
+    fn missing_ranges(&self) -> VecDeque<(u64, u64, usize)> {
+        missing_ranges(&saved_ranges(&self.folder), self.video.size, self.block_size)
     }
 
-    pub async fn download(&mut self, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    /// downloads the missing ranges with parallel connections, a connection without a range
+    /// takes over the second half of the largest one still running, so all stay busy until the end
+    pub async fn download(&mut self, options: &Options, cancel: &CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
         let segment_folder = self.folder.to_owned();
 
         if !segment_folder.exists() {
             match std::fs::create_dir(&segment_folder) {
                 Ok(_) => {}
                 Err(err) => {
-                    eprintln!("Error creating folder: {}", err);
+                    error!("Error creating folder: {}", err);
                     return Err(Box::new(err));
                 }
             }
         }
 
-        let segment_folder = Arc::new(segment_folder);
-        let semaphore = Arc::new(tokio::sync::Semaphore::new(options.max_parallel_downloads));
+        let pending = self.missing_ranges();
+        let downloaded = Arc::new(Mutex::new(self.video.size - pending.iter().map(|(start, end, _)| end - start + 1).sum::<u64>()));
 
-        let segments_downloaded = Arc::new(Mutex::new(0));
-        let total_segments = Arc::new(self.total_segments);
+        let segment_folder = Arc::new(segment_folder);
+        let concurrency = Arc::new(Concurrency::new(options));
+        let work = Arc::new(Mutex::new(Work { pending, in_flight: vec![] }));
 
-        let tasks = self.segments.to_owned().into_iter().map(|segment| {
+        let workers = (0..options.max_parallel_downloads.max(1)).map(|_| {
+            let video = Arc::clone(&self.video);
             let folder = Arc::clone(&segment_folder);
-            let semaphore = Arc::clone(&semaphore);
-            let segments_downloaded = Arc::clone(&segments_downloaded);
-            let total_segments = Arc::clone(&total_segments);
+            let concurrency = Arc::clone(&concurrency);
+            let work = Arc::clone(&work);
+            let downloaded = Arc::clone(&downloaded);
+            let scheduler = Arc::clone(&options.scheduler);
+            let cancel = cancel.clone();
+            let fsync = options.fsync;
+            let retries = options.max_download_retries.max(1);
             tokio::spawn(async move {
-                let _permit = semaphore.acquire().await.unwrap();
-                if let Err(err) = segment.download(folder).await {
-                    return Err(err);
+                loop {
+                    let permit = tokio::select! {
+                        _ = cancel.cancelled() => return Err(Box::new(CancelledError) as Box<dyn std::error::Error + Send>),
+                        permit = concurrency.acquire() => permit,
+                    };
+
+                    // the guards give the permit and the mirror back on every return
+                    let Some(range) = work.lock().unwrap().next() else {
+                        return Ok(());
+                    };
+
+                    let mirror = video.mirrors.pick();
+
+                    let slot = tokio::select! {
+                        _ = cancel.cancelled() => {
+                            work.lock().unwrap().done(&range);
+                            return Err(Box::new(CancelledError) as Box<dyn std::error::Error + Send>);
+                        }
+                        slot = scheduler.acquire(mirror.url()) => slot,
+                    };
+                    let started = Instant::now();
+                    let result = range.download(&video, mirror.url(), &folder, &cancel, fsync).await;
+                    std::mem::drop(slot);
+
+                    work.lock().unwrap().done(&range);
+
+                    let mut done = downloaded.lock().unwrap();
+                    *done += range.saved();
+
+                    match result {
+                        Ok(_) => {
+                            concurrency.finished(permit, range.saved());
+                            video.mirrors.finished(mirror, range.saved(), started.elapsed());
+
+                            info!("Downloaded {:.1} / {:.1} MB ({:5.2}%)\t ({}){}",
+                                *done as f64 / 1024. / 1024.,
+                                video.size as f64 / 1024. / 1024.,
+                                (*done as f64 / video.size as f64) * 100.,
+                                range.start,
+                                concurrency::format_concurrency(concurrency.current()));
+                            report::progress(*done, video.size);
+                        }
+                        Err(err) if err.is::<CancelledError>() => return Err(err),
+                        Err(err) => {
+                            concurrency.failed(permit, err.as_ref());
+
+                            // a range of a dropped mirror is downloaded from another one without counting as a try
+                            let tries = match video.mirrors.failed(mirror, err.as_ref()) {
+                                true => range.tries,
+                                false => range.tries + 1,
+                            };
+
+                            if tries >= retries {
+                                return Err(err);
+                            }
+
+                            // the bytes that arrived are kept, only the rest is downloaded again
+                            if let Some((start, end)) = range.rest() {
+                                info!("Retrying bytes {}-{}: {}", start, end, err);
+                                work.lock().unwrap().pending.push_back((start, end, tries));
+                            }
+                        }
+                    }
                 }
-
-                let mut segments_downloaded = segments_downloaded.lock().await;
-                *segments_downloaded += 1;
-
-                println!("Downloaded {:width$} / {:width$} segments ({:5.2}%)\t ({})",
-                    *segments_downloaded,
-                    total_segments,
-                    (*segments_downloaded as f64 / *total_segments as f64) * 100.,
-                    segment.id,
-                    width = total_segments.to_string().len());
-
-
-                Ok(segment)
             })
         }).collect::<Vec<_>>();
 
-        self.segments.clear();
+        // every worker is awaited, even after an error, so no write is left running when we return
+        let mut error: Option<Box<dyn std::error::Error>> = None;
 
-        for task in tasks {
-            match task.await {
-                Ok(Ok(segment)) => {
-                    self.segments.push(segment);
-                },
+        for worker in workers {
+            match worker.await {
+                Ok(Ok(())) => {},
+                Ok(Err(err)) if err.is::<CancelledError>() => {},
                 Ok(Err(err)) => {
-                    eprintln!("Error downloading segment: {}", err);
-                    return Err(err);
+                    error!("Error downloading range: {}", err);
+                    error.get_or_insert(err);
                 },
                 Err(err) => {
-                    eprintln!("Error waiting for task: {}", err);
-                    return Err(Box::new(err));
+                    error!("Error waiting for task: {}", err);
+                    error.get_or_insert(Box::new(err));
                 }
             }
         }
 
-        self.segments.sort_by(|a, b| a.id.cmp(&b.id));
+        if cancel.is_cancelled() {
+            info!("Download cancelled, the finished ranges are kept in {}", self.folder.to_string_lossy());
+            return Err(Box::new(CancelledError));
+        }
+
+        if let Some(err) = error {
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// checksum of the whole video the server sent
+    pub fn checksum(&self) -> Option<Checksum> {
+        self.video.mirrors.checksum()
+    }
+
+    pub fn combine(&self, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+        let mut file = PartFile::create(std::path::Path::new(&self.video.title))?;
+        combine(saved_ranges(&self.folder), &mut file)?;
+        file.finish(Some(self.video.size), options.fsync)?;
 
         Ok(())
     }
+}
+
+/// the range files in `folder` with their first and last byte, sorted by their start
+fn saved_ranges(folder: &Path) -> Vec<(u64, u64, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(folder) else {
+        return vec![];
+    };
+
+    let mut ranges = entries.filter_map(|entry| {
+        let path = entry.ok()?.path();
+        let start = path.file_name()?.to_str()?.strip_suffix(".range")?.parse::<u64>().ok()?;
+        let len = path.metadata().ok()?.len();
+
+        (len > 0).then_some((start, start + len - 1, path))
+    }).collect::<Vec<_>>();
 
-    pub fn combine(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if self.segments.len() != self.total_segments as usize {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "Not all segments downloaded")));
+    ranges.sort_by_key(|(start, _, _)| *start);
+
+    ranges
+}
+
+/// the bytes of a video of `size` that are not `saved`, in ranges of at most `block_size`
+fn missing_ranges(saved: &[(u64, u64, PathBuf)], size: u64, block_size: u64) -> VecDeque<(u64, u64, usize)> {
+    let mut missing = VecDeque::new();
+    let mut add = |mut start: u64, end: u64| {
+        while start <= end {
+            let block_end = (start + block_size - 1).min(end);
+            missing.push_back((start, block_end, 0));
+            start = block_end + 1;
         }
+    };
 
-        let mut segments = self.segments.to_owned();
-        segments.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut position = 0;
+    for (start, end, _) in saved {
+        if *start > position {
+            add(position, start - 1);
+        }
+        position = position.max(end + 1);
+    }
+
+    if position < size {
+        add(position, size - 1);
+    }
 
-        let mut file = std::fs::File::create(self.video.title.to_owned())?;
+    missing
+}
 
-        for segment in segments {
-            let seg_path = self.folder.join(format!("{}.ts", segment.id));
-            let mut seg_file = std::fs::File::open(seg_path)?;
+/// writes the `saved` ranges in order, the bytes of a range that overlap the ones before are skipped
+fn combine(saved: Vec<(u64, u64, PathBuf)>, output: &mut impl Write) -> std::io::Result<()> {
+    let mut position = 0;
 
-            std::io::copy(&mut seg_file, &mut file)?;
+    for (start, end, path) in saved {
+        if start > position {
+            return Err(std::io::Error::other("Not all ranges downloaded"));
+        }
+        if end < position {
+            continue;
         }
 
-        Ok(())
+        let mut range_file = std::fs::File::open(path)?;
+        range_file.seek(SeekFrom::Start(position - start))?;
+        std::io::copy(&mut range_file, output)?;
+
+        position = end + 1;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an empty folder of its own for range files
+    fn folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("downloader-range-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&folder);
+        std::fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn write_range(folder: &Path, start: u64, bytes: &[u8]) {
+        std::fs::write(folder.join(format!("{}.range", start)), bytes).unwrap();
+    }
+
+    /// saved ranges without a file, for the arithmetic only
+    fn ranges(ranges: &[(u64, u64)]) -> Vec<(u64, u64, PathBuf)> {
+        ranges.iter().map(|(start, end)| (*start, *end, PathBuf::new())).collect()
+    }
+
+    #[test]
+    fn split_needs_two_halves_worth_it() {
+        let range = VideoRange::new(0, 2 * MIN_SPLIT - 2, 0);
+        assert_eq!(range.split(), None);
+
+        let range = VideoRange::new(100, 100 + 2 * MIN_SPLIT - 1, 0);
+        assert_eq!(range.split(), Some((100 + MIN_SPLIT, 100 + 2 * MIN_SPLIT - 1)));
+        assert_eq!(range.remaining(), MIN_SPLIT);
+        // and the half that stayed is too small to split again
+        assert_eq!(range.split(), None);
+    }
+
+    #[test]
+    fn split_takes_half_of_what_is_left() {
+        let range = VideoRange::new(0, 6 * MIN_SPLIT - 1, 0);
+        range.progress.lock().unwrap().position = 2 * MIN_SPLIT + 1;
+
+        // an odd remainder leaves the larger half with the connection that takes over
+        assert_eq!(range.split(), Some((4 * MIN_SPLIT, 6 * MIN_SPLIT - 1)));
+        assert_eq!(range.rest(), Some((2 * MIN_SPLIT + 1, 4 * MIN_SPLIT - 1)));
+    }
+
+    #[test]
+    fn rest_is_empty_once_the_last_byte_is_written() {
+        let range = VideoRange::new(10, 19, 0);
+        assert_eq!(range.rest(), Some((10, 19)));
+
+        range.progress.lock().unwrap().position = 19;
+        assert_eq!(range.rest(), Some((19, 19)));
+
+        range.progress.lock().unwrap().position = 20;
+        assert_eq!(range.rest(), None);
+        assert_eq!(range.saved(), 10);
+    }
+
+    #[test]
+    fn missing_ranges_in_blocks() {
+        assert_eq!(missing_ranges(&[], 10, 4), VecDeque::from([(0, 3, 0), (4, 7, 0), (8, 9, 0)]));
+        assert_eq!(missing_ranges(&[], 8, 4), VecDeque::from([(0, 3, 0), (4, 7, 0)]));
+        assert_eq!(missing_ranges(&[], 0, 4), VecDeque::new());
+    }
+
+    #[test]
+    fn missing_ranges_around_saved_ones() {
+        // the gaps before, between and after the saved ranges, touching ranges leave no gap
+        let saved = ranges(&[(2, 3), (4, 5), (8, 8)]);
+        assert_eq!(missing_ranges(&saved, 12, 4), VecDeque::from([(0, 1, 0), (6, 7, 0), (9, 11, 0)]));
+
+        // nothing is missing once the last byte is saved
+        assert_eq!(missing_ranges(&ranges(&[(0, 11)]), 12, 4), VecDeque::new());
+    }
+
+    #[test]
+    fn missing_ranges_with_overlapping_saved_ones() {
+        let saved = ranges(&[(0, 5), (2, 3), (4, 7)]);
+        assert_eq!(missing_ranges(&saved, 10, 4), VecDeque::from([(8, 9, 0)]));
+    }
+
+    #[test]
+    fn saved_ranges_skip_empty_and_foreign_files() {
+        let folder = folder("saved");
+        write_range(&folder, 4, b"efgh");
+        write_range(&folder, 0, b"abcd");
+        write_range(&folder, 8, b"");
+        std::fs::write(folder.join("notes.txt"), b"x").unwrap();
+
+        let ranges = saved_ranges(&folder).into_iter().map(|(start, end, _)| (start, end)).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![(0, 3), (4, 7)]);
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn combine_skips_overlapping_bytes() {
+        let folder = folder("overlap");
+        write_range(&folder, 0, b"abcd");
+        write_range(&folder, 2, b"cd");
+        write_range(&folder, 3, b"defg");
+
+        let mut output = vec![];
+        combine(saved_ranges(&folder), &mut output).unwrap();
+        assert_eq!(output, b"abcdefg");
+
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    fn combine_fails_on_a_gap() {
+        let folder = folder("gap");
+        write_range(&folder, 0, b"abc");
+        write_range(&folder, 4, b"efg");
+
+        assert!(combine(saved_ranges(&folder), &mut vec![]).is_err());
+
+        std::fs::remove_dir_all(&folder).unwrap();
     }
 }
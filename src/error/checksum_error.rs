@@ -0,0 +1,18 @@
+use std::path::PathBuf;
+
+use crate::download::checksum::Checksum;
+
+#[derive(Debug, Clone)]
+pub struct ChecksumError {
+    pub output: PathBuf,
+    pub expected: Checksum,
+    pub actual: Checksum,
+}
+
+impl std::fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Checksum of {} is {} but {} was expected", self.output.to_string_lossy(), self.actual, self.expected)
+    }
+}
+
+impl std::error::Error for ChecksumError {}
@@ -0,0 +1,14 @@
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub struct DuplicateError {
+    pub output: PathBuf,
+}
+
+impl std::fmt::Display for DuplicateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Already downloaded to {}", self.output.to_string_lossy())
+    }
+}
+
+impl std::error::Error for DuplicateError {}
@@ -0,0 +1,14 @@
+use std::path::PathBuf;
+
+#[derive(Debug, Clone)]
+pub struct ExistsError {
+    pub output: PathBuf,
+}
+
+impl std::fmt::Display for ExistsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "File {} already exists", self.output.to_string_lossy())
+    }
+}
+
+impl std::error::Error for ExistsError {}
@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+use crate::serve::queue::JobStatus;
+
+#[derive(Debug, Clone)]
+pub enum JobError {
+    NotFound(u64),
+    InvalidState(u64, JobStatus),
+    Store(String),
+    /// the job sets tls settings, only the ones the daemon was started with are used
+    Tls,
+    /// the output is absolute or leaves the output directory
+    Output(PathBuf),
+}
+
+impl std::fmt::Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JobError::NotFound(id) => write!(f, "Job {} not found", id),
+            JobError::InvalidState(id, status) => write!(f, "Job {} is {}", id, status.as_str()),
+            JobError::Store(err) => write!(f, "Error saving job: {}", err),
+            JobError::Tls => write!(f, "Jobs can not set tls settings, they are configured when the daemon is started"),
+            JobError::Output(output) => write!(f, "Output {} must be a relative path inside the output directory", output.to_string_lossy()),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
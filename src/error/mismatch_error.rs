@@ -0,0 +1,16 @@
+use url::Url;
+
+/// the content of a mirror does not match the one of the download url
+#[derive(Debug, Clone)]
+pub struct MismatchError {
+    pub url: Url,
+    pub reason: String,
+}
+
+impl std::fmt::Display for MismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Mirror {} does not match: {}", self.url, self.reason)
+    }
+}
+
+impl std::error::Error for MismatchError {}
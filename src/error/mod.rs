@@ -1 +1,8 @@
+pub mod cancelled_error;
+pub mod checksum_error;
+pub mod duplicate_error;
+pub mod exists_error;
 pub mod extension_error;
+pub mod job_error;
+pub mod mismatch_error;
+pub mod segment_error;
@@ -0,0 +1,14 @@
+/// the body of a segment is not media, e.g. an error page the server sent with status 200
+#[derive(Debug, Clone)]
+pub struct SegmentError {
+    pub name: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Segment {} is invalid: {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for SegmentError {}
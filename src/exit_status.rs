@@ -0,0 +1,22 @@
+use std::process::ExitCode;
+
+/// exit status of the whole run, returned by `main`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// every download finished or was skipped because it already exists
+    Success = 0,
+    /// the single download or every download of a batch failed
+    Failure = 1,
+    /// the arguments or the batch file could not be used
+    Usage = 2,
+    /// some, but not all, downloads of a batch failed
+    PartialFailure = 3,
+    /// the run was stopped by SIGINT or SIGTERM
+    Interrupted = 130,
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> Self {
+        ExitCode::from(status as u8)
+    }
+}
@@ -1,16 +1,175 @@
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use crate::{download, options::Options};
-use serde::Deserialize;
+use crate::error::{cancelled_error::CancelledError, duplicate_error::DuplicateError, exists_error::ExistsError};
+use crate::store::{self, Store};
+use crate::download::{self, checksum::Checksum, tls, DownloadClient};
+use crate::{exit_status::ExitStatus, options::{Options, TlsOptions}, report};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+use url::Url;
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
-struct DownloadEntity {
-    url: String,
-    output: PathBuf,
+pub struct DownloadEntity {
+    pub url: String,
+    /// rendered from the output template if not set
+    #[serde(default)]
+    pub output: Option<PathBuf>,
+    /// proxy of this download instead of the one of the options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// tls settings added to the ones of the options
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsOptions>,
+    /// other urls of the same video file, see `Options::mirrors`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    /// expected checksum of the file, e.g. `sha256:<hex>`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
 }
 
-pub async fn download_file(file: &str, options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+impl DownloadEntity {
+    /// a copy for the store, the credentials of the url, the proxy and the mirrors are only kept in memory
+    pub fn without_credentials(&self) -> DownloadEntity {
+        let mut entity = self.clone();
+        entity.url = report::redact(&entity.url);
+        entity.proxy = entity.proxy.as_deref().map(report::redact);
+        entity.mirrors = entity.mirrors.iter().map(|mirror| report::redact(mirror)).collect();
+
+        entity
+    }
+
+    /// whether credentials were removed by `without_credentials`, the download can not use them
+    pub fn lost_credentials(&self) -> bool {
+        std::iter::once(&self.url).chain(&self.proxy).chain(&self.mirrors).any(|url| url.contains("://***@"))
+    }
+
+    /// the shared client, or one of its own if this download has its own proxy or tls settings
+    pub fn client(&self, options: &Options, shared: &DownloadClient) -> Result<DownloadClient, Box<dyn std::error::Error>> {
+        match self.proxy.is_none() && self.tls.is_none() {
+            true => Ok(shared.clone()),
+            false => DownloadClient::new(options),
+        }
+    }
+
+    /// `options` with the settings of this download applied
+    pub fn options(&self, options: &Options) -> Result<Options, Box<dyn std::error::Error>> {
+        let mut options = options.clone();
+
+        if let Some(proxy) = &self.proxy {
+            if let Err(err) = download::proxy(proxy) {
+                return Err(format!("Invalid proxy {}: {}", report::redact(proxy), err).into());
+            }
+            options.proxy = Some(proxy.clone());
+        }
+
+        options.mirrors = match self.mirrors.iter().map(|mirror| Url::parse(mirror).map_err(|err| (mirror, err))).collect() {
+            Ok(mirrors) => mirrors,
+            Err((mirror, err)) => return Err(format!("Invalid mirror {}: {}", report::redact(mirror), err).into()),
+        };
+
+        options.checksum = match self.checksum.as_deref().map(Checksum::from_str).transpose() {
+            Ok(checksum) => checksum,
+            Err(err) => return Err(format!("Invalid checksum: {}", err).into()),
+        };
+
+        if let Some(tls) = &self.tls {
+            options.tls.merge(tls);
+
+            for warning in tls::insecure_warnings(tls) {
+                error!("{}", warning);
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Succeeded,
+    Skipped(String),
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct SummaryEntry {
+    pub url: String,
+    /// not known for entries without an output that did not get to render their template
+    pub output: Option<PathBuf>,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub entries: Vec<SummaryEntry>,
+}
+
+impl SummaryEntry {
+    fn output_name(&self) -> String {
+        self.output.as_ref().map_or("-".to_string(), |output| output.to_string_lossy().to_string())
+    }
+}
+
+impl Summary {
+    fn push(&mut self, download: DownloadEntity, outcome: Outcome) {
+        self.entries.push(SummaryEntry {
+            url: download.url,
+            output: download.output,
+            outcome,
+        });
+    }
+
+    fn count(&self, matches: fn(&Outcome) -> bool) -> usize {
+        self.entries.iter().filter(|entry| matches(&entry.outcome)).count()
+    }
+
+    pub fn exit_status(&self) -> ExitStatus {
+        let failed = self.count(|outcome| matches!(outcome, Outcome::Failed(_)));
+
+        if failed == 0 {
+            ExitStatus::Success
+        } else if failed == self.entries.len() {
+            ExitStatus::Failure
+        } else {
+            ExitStatus::PartialFailure
+        }
+    }
+
+    pub fn print(&self) {
+        let status_width = "succeeded".len();
+        let output_width = self.entries.iter()
+            .map(|entry| entry.output_name().len())
+            .chain(std::iter::once("OUTPUT".len()))
+            .max()
+            .unwrap_or_default();
+
+        println!("Summary:");
+        println!("{:status_width$}  {:output_width$}  DETAILS", "STATUS", "OUTPUT");
+
+        for entry in &self.entries {
+            let (status, details) = match &entry.outcome {
+                Outcome::Succeeded => ("succeeded", entry.url.as_str()),
+                Outcome::Skipped(reason) => ("skipped", reason.as_str()),
+                Outcome::Failed(reason) => ("failed", reason.as_str()),
+            };
+
+            println!("{:status_width$}  {:output_width$}  {}", status, entry.output_name(), report::redact(details));
+        }
+
+        println!("{} entries: {} succeeded, {} skipped, {} failed",
+            self.entries.len(),
+            self.count(|outcome| matches!(outcome, Outcome::Succeeded)),
+            self.count(|outcome| matches!(outcome, Outcome::Skipped(_))),
+            self.count(|outcome| matches!(outcome, Outcome::Failed(_))));
+    }
+}
+
+/// downloads every entry of the json file, an error is only returned if the
+/// file itself can not be read, failed entries are recorded in the summary
+pub async fn download_file(file: &str, options: &Options, client: &DownloadClient, cancel: &CancellationToken, store: &Store) -> Result<Summary, Box<dyn std::error::Error>> {
     let file = match std::fs::File::open(file) {
         Ok(file) => file,
         Err(err) => {
@@ -27,29 +186,62 @@ pub async fn download_file(file: &str, options: &Options) -> Result<(), Box<dyn
         }
     };
 
+    let mut summary = Summary::default();
+
     for download in downloads {
+        if cancel.is_cancelled() {
+            summary.push(download, Outcome::Skipped("interrupted".to_string()));
+            continue;
+        }
+
         println!();
 
-        if Path::new(&download.output).exists() {
-            println!("File {} already exists, therefore skipping download", download.output.to_string_lossy());
+        if let Some(output) = download.output.as_deref().filter(|output| output.exists()) {
+            println!("File {} already exists, therefore skipping download", output.to_string_lossy());
+            summary.push(download, Outcome::Skipped("already exists".to_string()));
             continue;
         }
 
-        println!("Downloading {} to {}", download.url, download.output.to_string_lossy());
+        let options_and_client = download.options(options)
+            .and_then(|options| download.client(&options, client).map(|client| (options, client)));
+
+        let (options, client) = match options_and_client {
+            Ok(options_and_client) => options_and_client,
+            Err(err) => {
+                eprintln!("Error downloading {}: {}", report::redact(&download.url), err);
+                summary.push(download, Outcome::Failed(err.to_string()));
+                continue;
+            }
+        };
 
-        match download::search::download(&download.url, &download.output, options).await {
-            Ok(_) => {
-                println!("Finished downloading {} to {}", download.url, download.output.to_string_lossy());
+        match &download.output {
+            Some(output) => println!("Downloading {} to {}", report::redact(&download.url), output.to_string_lossy()),
+            None => println!("Downloading {}", report::redact(&download.url)),
+        }
+
+        match store::download(&download.url, download.output.as_deref(), &options, &client, cancel, store).await {
+            Ok(downloaded) => {
+                println!("Finished downloading {} to {}", report::redact(&download.url), downloaded.output.to_string_lossy());
                 println!();
+                summary.push(DownloadEntity { output: Some(downloaded.output), ..download }, Outcome::Succeeded);
+            },
+            Err(err) if err.is::<ExistsError>() => {
+                println!("{}, therefore skipping download", err);
+                summary.push(download, Outcome::Skipped("already exists".to_string()));
+            },
+            Err(err) if err.is::<CancelledError>() => {
+                summary.push(download, Outcome::Skipped("interrupted".to_string()));
+            },
+            Err(err) if err.is::<DuplicateError>() => {
+                summary.push(download, Outcome::Skipped(err.to_string()));
             },
             Err(err) => {
-                eprintln!("Error downloading {}: {}", download.url, err);
+                eprintln!("Error downloading {}: {}", report::redact(&download.url), report::redact(&err.to_string()));
                 eprintln!();
+                summary.push(download, Outcome::Failed(report::redact(&err.to_string())));
             }
         }
     }
 
-    Ok(())
+    Ok(summary)
 }
-
-
@@ -0,0 +1,28 @@
+//! The downloader as a library, so extractors for more sites can live in their own crates and
+//! run with the command line of the downloader:
+//! ```ignore
+//! #[tokio::main]
+//! async fn main() -> std::process::ExitCode {
+//!     downloader_rs::register(std::sync::Arc::new(MySiteExtractor));
+//!     downloader_rs::run().await
+//! }
+//! ```
+
+#[macro_use]
+mod report;
+mod cli;
+mod file;
+mod download;
+mod options;
+mod error;
+mod exit_status;
+mod serve;
+mod shutdown;
+mod store;
+mod cookies;
+mod config;
+mod netrc;
+
+pub use cli::run;
+pub use download::DownloadClient;
+pub use download::extractor::{register, Extractor, MediaDescriptor, Variant};
@@ -0,0 +1,114 @@
+use std::path::{Path, PathBuf};
+
+use crate::options::{Auth, HostCredentials};
+
+/// `~/.netrc`
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| Path::new(&home).join(".netrc"))
+}
+
+/// the `machine` entries of a netrc file as basic auth credentials, the `default` entry is
+/// ignored so no credentials are sent to hosts that are not listed
+pub fn load(path: &Path) -> Result<Vec<HostCredentials>, Box<dyn std::error::Error>> {
+    Ok(parse(&std::fs::read_to_string(path)?))
+}
+
+fn parse(content: &str) -> Vec<HostCredentials> {
+    let mut credentials = Vec::new();
+    let mut machine: Option<String> = None;
+    let mut login: Option<String> = None;
+    let mut password: Option<String> = None;
+
+    let mut finish = |machine: &mut Option<String>, login: &mut Option<String>, password: &mut Option<String>| {
+        if let (Some(host), Some(password)) = (machine.take(), password.take()) {
+            credentials.push(HostCredentials {
+                host,
+                auth: Auth::Basic { username: login.take().unwrap_or_default(), password },
+            });
+        }
+        *login = None;
+    };
+
+    let mut in_macro = false;
+
+    for line in content.lines() {
+        // a macro definition runs until the next empty line
+        if in_macro {
+            in_macro = !line.trim().is_empty();
+            continue;
+        }
+
+        let mut tokens = line.split_whitespace();
+
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" => {
+                    finish(&mut machine, &mut login, &mut password);
+                    machine = tokens.next().map(str::to_string);
+                }
+                "default" => {
+                    finish(&mut machine, &mut login, &mut password);
+                }
+                "login" => login = tokens.next().map(str::to_string),
+                "password" => password = tokens.next().map(str::to_string),
+                "account" => {
+                    tokens.next();
+                }
+                "macdef" => {
+                    in_macro = true;
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    finish(&mut machine, &mut login, &mut password);
+
+    credentials
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hosts(content: &str) -> Vec<(String, String, String)> {
+        parse(content).into_iter().map(|credentials| match credentials.auth {
+            Auth::Basic { username, password } => (credentials.host, username, password),
+            Auth::Bearer { .. } => unreachable!(),
+        }).collect()
+    }
+
+    fn entry(host: &str, username: &str, password: &str) -> (String, String, String) {
+        (host.to_string(), username.to_string(), password.to_string())
+    }
+
+    #[test]
+    fn machines_on_one_or_more_lines() {
+        let content = "machine a.example.com login alice password one\n\nmachine b.example.com\n  login bob\n  account x\n  password two\n";
+
+        assert_eq!(hosts(content), vec![entry("a.example.com", "alice", "one"), entry("b.example.com", "bob", "two")]);
+    }
+
+    #[test]
+    fn default_is_ignored() {
+        let content = "machine a.example.com login alice password one\ndefault login anonymous password guest\n";
+        assert_eq!(hosts(content), vec![entry("a.example.com", "alice", "one")]);
+
+        // its password does not end up at the machine after it
+        let content = "default login anonymous password guest\nmachine a.example.com login alice\n";
+        assert_eq!(hosts(content), vec![]);
+    }
+
+    #[test]
+    fn macdef_runs_until_an_empty_line() {
+        let content = "macdef init\nmachine evil.example.com password leaked\ncd /\n\nmachine a.example.com password one\n";
+
+        assert_eq!(hosts(content), vec![entry("a.example.com", "", "one")]);
+    }
+
+    #[test]
+    fn machine_without_password_is_skipped() {
+        assert_eq!(hosts("machine a.example.com login alice\nmachine b.example.com password two"), vec![entry("b.example.com", "", "two")]);
+    }
+}
@@ -0,0 +1,121 @@
+pub mod queue;
+
+use std::sync::Arc;
+
+use axum::extract::{self, Request, State};
+use axum::http::{header::AUTHORIZATION, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use tokio_util::sync::CancellationToken;
+
+use crate::download::DownloadClient;
+use crate::error::job_error::JobError;
+use crate::file::DownloadEntity;
+use crate::options::Options;
+use crate::store::Store;
+use queue::{Job, Queue};
+
+impl IntoResponse for JobError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            JobError::NotFound(_) => StatusCode::NOT_FOUND,
+            JobError::InvalidState(..) => StatusCode::CONFLICT,
+            JobError::Store(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            JobError::Tls | JobError::Output(_) => StatusCode::BAD_REQUEST,
+        };
+
+        (status, self.to_string()).into_response()
+    }
+}
+
+/// every request has to send `Authorization: Bearer <token>`, other local users could
+/// otherwise control the daemon
+async fn authorize(State(token): State<Arc<String>>, request: Request, next: Next) -> Response {
+    let sent = request.headers().get(AUTHORIZATION)
+        .and_then(|authorization| authorization.to_str().ok())
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // compared in full, so the time does not tell how much of the token was right
+    let matches = sent.len() == token.len() && sent.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0;
+
+    match matches {
+        true => next.run(request).await,
+        false => (StatusCode::UNAUTHORIZED, "Missing or wrong token").into_response(),
+    }
+}
+
+async fn submit_job(State(queue): State<Arc<Queue>>, Json(entity): Json<DownloadEntity>) -> Result<(StatusCode, Json<Job>), JobError> {
+    queue.submit(entity).map(|job| (StatusCode::CREATED, Json(job)))
+}
+
+async fn list_jobs(State(queue): State<Arc<Queue>>) -> Json<Vec<Job>> {
+    Json(queue.list())
+}
+
+async fn show_job(State(queue): State<Arc<Queue>>, extract::Path(id): extract::Path<u64>) -> Result<Json<Job>, JobError> {
+    queue.get(id).map(Json)
+}
+
+async fn job_log(State(queue): State<Arc<Queue>>, extract::Path(id): extract::Path<u64>) -> Result<String, JobError> {
+    queue.log(id).map(|lines| lines.join("\n") + "\n")
+}
+
+async fn pause_job(State(queue): State<Arc<Queue>>, extract::Path(id): extract::Path<u64>) -> Result<Json<Job>, JobError> {
+    queue.pause(id).map(Json)
+}
+
+async fn resume_job(State(queue): State<Arc<Queue>>, extract::Path(id): extract::Path<u64>) -> Result<Json<Job>, JobError> {
+    queue.resume(id).map(Json)
+}
+
+async fn cancel_job(State(queue): State<Arc<Queue>>, extract::Path(id): extract::Path<u64>) -> Result<Json<Job>, JobError> {
+    queue.cancel(id).map(Json)
+}
+
+/// runs the job queue and serves the control api on `listen` for the clients that know `token`
+/// until `cancel` is cancelled
+pub async fn serve(listen: &str, token: String, store: Arc<Store>, options: &Options, client: &DownloadClient, cancel: &CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    let queue = match Queue::load(store, &options.output_dir) {
+        Ok(queue) => Arc::new(queue),
+        Err(err) => {
+            eprintln!("Error loading jobs: {}", err);
+            return Err(err);
+        }
+    };
+
+    let router = Router::new()
+        .route("/jobs", get(list_jobs).post(submit_job))
+        .route("/jobs/{id}", get(show_job))
+        .route("/jobs/{id}/log", get(job_log))
+        .route("/jobs/{id}/pause", post(pause_job))
+        .route("/jobs/{id}/resume", post(resume_job))
+        .route("/jobs/{id}/cancel", post(cancel_job))
+        .with_state(Arc::clone(&queue))
+        .layer(middleware::from_fn_with_state(Arc::new(token), authorize));
+
+    let listener = match tokio::net::TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Error listening on {}: {}", listen, err);
+            return Err(Box::new(err));
+        }
+    };
+
+    println!("Listening on http://{}", listen);
+
+    let shutdown = cancel.clone();
+    let server = axum::serve(listener, router)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await });
+
+    let (result, _) = tokio::join!(server, queue::work(queue, options, client, cancel));
+
+    if let Err(err) = result {
+        eprintln!("Error serving api: {}", err);
+        return Err(Box::new(err));
+    }
+
+    Ok(())
+}
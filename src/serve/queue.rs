@@ -0,0 +1,351 @@
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::download::{DownloadClient, Downloaded};
+use crate::error::{cancelled_error::CancelledError, job_error::JobError};
+use crate::file::DownloadEntity;
+use crate::options::Options;
+use crate::report::{self, Report};
+use crate::store::{self, Store};
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Paused,
+    Cancelled,
+    Succeeded,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Paused => "paused",
+            JobStatus::Cancelled => "cancelled",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = String;
+
+    fn from_str(status: &str) -> Result<Self, Self::Err> {
+        match status {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "paused" => Ok(JobStatus::Paused),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            other => Err(format!("Unknown job status {}", other)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Progress {
+    pub done: u64,
+    pub total: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Job {
+    pub id: u64,
+    #[serde(flatten)]
+    pub entity: DownloadEntity,
+    pub status: JobStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub progress: Progress,
+    #[serde(skip)]
+    report: Arc<Report>,
+}
+
+struct Running {
+    id: u64,
+    cancel: CancellationToken,
+    /// the status the job gets once it stopped, set by pause and cancel
+    stop_as: Option<JobStatus>,
+}
+
+#[derive(Default)]
+struct State {
+    jobs: Vec<Job>,
+    running: Option<Running>,
+}
+
+/// jobs of the daemon, every change is written to the store
+pub struct Queue {
+    store: Arc<Store>,
+    /// the outputs of the jobs are placed below it
+    output_dir: PathBuf,
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl Queue {
+    pub fn load(store: Arc<Store>, output_dir: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut jobs = store.jobs()?;
+
+        // jobs that were running when the daemon stopped are picked up again
+        for job in jobs.iter_mut().filter(|job| job.status == JobStatus::Running) {
+            job.status = JobStatus::Queued;
+            store.save_job(job)?;
+        }
+
+        // unless the credentials they need were not stored
+        for job in jobs.iter_mut().filter(|job| matches!(job.status, JobStatus::Queued | JobStatus::Paused) && job.entity.lost_credentials()) {
+            job.status = JobStatus::Failed;
+            job.error = Some("The credentials of the url, the proxy and the mirrors are not stored, submit the job again".to_string());
+            store.save_job(job)?;
+        }
+
+        Ok(Self {
+            store,
+            output_dir: output_dir.to_path_buf(),
+            state: Mutex::new(State { jobs, running: None }),
+            notify: Notify::new(),
+        })
+    }
+
+    fn save(&self, job: &Job) {
+        if let Err(err) = self.store.save_job(job) {
+            eprintln!("Error saving job {}: {}", job.id, err);
+        }
+    }
+
+    fn update<T>(&self, id: u64, change: impl FnOnce(&mut Job, &mut Option<Running>) -> Result<T, JobError>) -> Result<T, JobError> {
+        let mut state = self.state.lock().unwrap();
+        let State { jobs, running } = &mut *state;
+
+        let job = jobs.iter_mut().find(|job| job.id == id).ok_or(JobError::NotFound(id))?;
+        let result = change(job, running)?;
+
+        self.save(job);
+
+        Ok(result)
+    }
+
+    pub fn submit(&self, mut entity: DownloadEntity) -> Result<Job, JobError> {
+        // a job could otherwise trust any certificate or read any file of the daemon as one
+        if entity.tls.is_some() {
+            return Err(JobError::Tls);
+        }
+
+        // or write any file the daemon can write
+        if let Some(output) = &entity.output {
+            entity.output = Some(resolve_output(&self.output_dir, output)?);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        let id = match self.store.insert_job(&entity) {
+            Ok(id) => id,
+            Err(err) => {
+                eprintln!("Error saving job: {}", err);
+                return Err(JobError::Store(err.to_string()));
+            }
+        };
+
+        let job = Job::new(id, entity, JobStatus::Queued, None, Progress::default());
+        state.jobs.push(job.clone());
+
+        self.notify.notify_one();
+
+        Ok(job.snapshot())
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        let state = self.state.lock().unwrap();
+        state.jobs.iter().map(Job::snapshot).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Result<Job, JobError> {
+        let state = self.state.lock().unwrap();
+        state.jobs.iter().find(|job| job.id == id).map(Job::snapshot).ok_or(JobError::NotFound(id))
+    }
+
+    pub fn log(&self, id: u64) -> Result<Vec<String>, JobError> {
+        let state = self.state.lock().unwrap();
+        state.jobs.iter().find(|job| job.id == id).map(|job| job.report.log()).ok_or(JobError::NotFound(id))
+    }
+
+    pub fn pause(&self, id: u64) -> Result<Job, JobError> {
+        self.stop(id, JobStatus::Paused)
+    }
+
+    pub fn cancel(&self, id: u64) -> Result<Job, JobError> {
+        self.stop(id, JobStatus::Cancelled)
+    }
+
+    /// a queued or paused job gets `status` immediately, a running one once its download stopped
+    fn stop(&self, id: u64, status: JobStatus) -> Result<Job, JobError> {
+        self.update(id, |job, running| {
+            match job.status {
+                JobStatus::Queued | JobStatus::Paused => job.status = status,
+                JobStatus::Running => {
+                    if let Some(running) = running.as_mut().filter(|running| running.id == id) {
+                        running.stop_as = Some(status);
+                        running.cancel.cancel();
+                    }
+                }
+                other => return Err(JobError::InvalidState(id, other)),
+            }
+
+            Ok(job.snapshot())
+        })
+    }
+
+    pub fn resume(&self, id: u64) -> Result<Job, JobError> {
+        let job = self.update(id, |job, _| {
+            match job.status {
+                JobStatus::Paused | JobStatus::Failed | JobStatus::Cancelled => {
+                    job.status = JobStatus::Queued;
+                    job.error = None;
+                }
+                other => return Err(JobError::InvalidState(id, other)),
+            }
+
+            Ok(job.snapshot())
+        })?;
+
+        self.notify.notify_one();
+
+        Ok(job)
+    }
+
+    /// marks the first queued job as running
+    fn start(&self, cancel: &CancellationToken) -> Option<(Job, CancellationToken)> {
+        let mut state = self.state.lock().unwrap();
+        let State { jobs, running } = &mut *state;
+
+        let job = jobs.iter_mut().find(|job| job.status == JobStatus::Queued)?;
+        job.status = JobStatus::Running;
+        job.report = Arc::default();
+
+        let cancel = cancel.child_token();
+        *running = Some(Running { id: job.id, cancel: cancel.clone(), stop_as: None });
+
+        self.save(job);
+
+        Some((job.clone(), cancel))
+    }
+
+    fn finish(&self, id: u64, result: Result<Downloaded, Box<dyn std::error::Error>>) {
+        let stop_as = self.state.lock().unwrap().running.take().and_then(|running| running.stop_as);
+
+        let _ = self.update(id, |job, _| {
+            job.progress = job.snapshot().progress;
+            (job.status, job.error) = match result {
+                Ok(downloaded) => {
+                    job.entity.output = Some(downloaded.output);
+                    (JobStatus::Succeeded, None)
+                }
+                // stopped by the daemon shutting down, so it is run again on the next start
+                Err(err) if err.is::<CancelledError>() => (stop_as.unwrap_or(JobStatus::Queued), None),
+                Err(err) => (JobStatus::Failed, Some(report::redact(&err.to_string()))),
+            };
+
+            Ok(())
+        });
+    }
+}
+
+/// `output` below `output_dir`, absolute outputs and `..` are refused
+fn resolve_output(output_dir: &Path, output: &Path) -> Result<PathBuf, JobError> {
+    let is_relative = output.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+    match is_relative && output.file_name().is_some() {
+        true => Ok(output_dir.join(output)),
+        false => Err(JobError::Output(output.to_path_buf())),
+    }
+}
+
+impl Job {
+    pub fn new(id: u64, entity: DownloadEntity, status: JobStatus, error: Option<String>, progress: Progress) -> Self {
+        Self { id, entity, status, error, progress, report: Arc::default() }
+    }
+
+    /// a copy for the api, with the current progress and without credentials
+    fn snapshot(&self) -> Job {
+        let mut job = self.clone();
+        job.entity.url = report::redact(&job.entity.url);
+        job.entity.proxy = job.entity.proxy.as_deref().map(report::redact);
+        job.entity.mirrors = job.entity.mirrors.iter().map(|mirror| report::redact(mirror)).collect();
+
+        if job.status == JobStatus::Running {
+            let (done, total) = job.report.progress();
+            job.progress = Progress { done, total };
+        }
+
+        job
+    }
+}
+
+/// runs the queued jobs one after another until `cancel` is cancelled
+pub async fn work(queue: Arc<Queue>, options: &Options, client: &DownloadClient, cancel: &CancellationToken) {
+    let store = Arc::clone(&queue.store);
+
+    while !cancel.is_cancelled() {
+        let Some((job, job_cancel)) = queue.start(cancel) else {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                _ = queue.notify.notified() => {}
+            }
+            continue;
+        };
+
+        match &job.entity.output {
+            Some(output) => println!("Starting job {}: {} to {}", job.id, report::redact(&job.entity.url), output.to_string_lossy()),
+            None => println!("Starting job {}: {}", job.id, report::redact(&job.entity.url)),
+        }
+
+        report::attach(Arc::clone(&job.report));
+        // jobs stored before their tls settings were refused are not run either
+        let options_and_client = match job.entity.tls {
+            Some(_) => Err(JobError::Tls.into()),
+            None => job.entity.options(options)
+                .and_then(|options| job.entity.client(&options, client).map(|client| (options, client))),
+        };
+
+        let result = match options_and_client {
+            Ok((options, client)) => store::download(&job.entity.url, job.entity.output.as_deref(), &options, &client, &job_cancel, &store).await,
+            Err(err) => {
+                error!("{}", err);
+                Err(err)
+            }
+        };
+        report::detach();
+
+        queue.finish(job.id, result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_output_stays_in_the_output_dir() {
+        let output_dir = Path::new("videos");
+
+        assert_eq!(resolve_output(output_dir, Path::new("a.mp4")).unwrap(), Path::new("videos/a.mp4"));
+        assert_eq!(resolve_output(output_dir, Path::new("./show/a.mp4")).unwrap(), Path::new("videos/./show/a.mp4"));
+
+        for output in ["/home/user/.ssh/authorized_keys", "../a.mp4", "show/../../a.mp4", "show/..", ""] {
+            assert!(resolve_output(output_dir, Path::new(output)).is_err(), "{}", output);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use tokio::signal;
+use tokio_util::sync::CancellationToken;
+
+use crate::exit_status::ExitStatus;
+
+/// cancels the token on the first SIGINT/SIGTERM so that no new requests are
+/// started and the running ones can finish their writes, a second signal exits immediately
+pub fn listen(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        eprintln!();
+        eprintln!("Stopping, waiting for running writes to finish (press Ctrl-C again to force exit)");
+        cancel.cancel();
+
+        wait_for_signal().await;
+        eprintln!("Forcing exit");
+        std::process::exit(ExitStatus::Interrupted as i32);
+    });
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            eprintln!("Error listening for SIGTERM: {}", err);
+            let _ = signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = signal::ctrl_c().await;
+}
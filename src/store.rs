@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use rusqlite::types::Type;
+use tokio_util::sync::CancellationToken;
+
+use crate::download::{self, DownloadClient, Downloaded};
+use crate::error::duplicate_error::DuplicateError;
+use crate::file::DownloadEntity;
+use crate::options::Options;
+use crate::report;
+use crate::serve::queue::{Job, JobStatus, Progress};
+
+/// a finished download as recorded in the history
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub url: String,
+    pub output: PathBuf,
+    pub media_url: String,
+    pub variant: Option<String>,
+    pub size: u64,
+    pub finished_at: String,
+}
+
+/// sqlite database with the jobs of the daemon and the history of finished downloads
+pub struct Store {
+    connection: Mutex<Connection>,
+}
+
+impl Store {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+
+        // the output of a job is empty until the download picked a name
+        connection.execute_batch("
+            CREATE TABLE IF NOT EXISTS jobs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL,
+                output TEXT,
+                entity TEXT NOT NULL,
+                status TEXT NOT NULL,
+                error TEXT,
+                done INTEGER NOT NULL DEFAULT 0,
+                total INTEGER NOT NULL DEFAULT 0
+            );
+
+            CREATE TABLE IF NOT EXISTS downloads (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL,
+                output TEXT NOT NULL,
+                media_url TEXT NOT NULL,
+                variant TEXT,
+                size INTEGER NOT NULL,
+                finished_at INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS downloads_url ON downloads (url);
+        ")?;
+
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    pub fn jobs(&self) -> rusqlite::Result<Vec<Job>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT id, entity, status, error, done, total FROM jobs ORDER BY id")?;
+
+        let jobs = statement.query_map([], |row| {
+            let entity: String = row.get(1)?;
+            let entity = serde_json::from_str(&entity).map_err(|err| rusqlite::Error::FromSqlConversionFailure(1, Type::Text, Box::new(err)))?;
+            let status: String = row.get(2)?;
+
+            Ok(Job::new(
+                row.get(0)?,
+                entity,
+                status.parse().unwrap_or(JobStatus::Failed),
+                row.get(3)?,
+                Progress { done: row.get(4)?, total: row.get(5)? },
+            ))
+        })?;
+
+        jobs.collect()
+    }
+
+    /// adds a queued job and returns its id
+    pub fn insert_job(&self, entity: &DownloadEntity) -> rusqlite::Result<u64> {
+        let entity = entity.without_credentials();
+
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO jobs (url, output, entity, status) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                entity.url,
+                entity.output.as_ref().map(|output| output.to_string_lossy()),
+                serde_json::to_string(&entity).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?,
+                JobStatus::Queued.as_str(),
+            ],
+        )?;
+
+        Ok(connection.last_insert_rowid() as u64)
+    }
+
+    pub fn save_job(&self, job: &Job) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE jobs SET output = ?2, entity = ?3, status = ?4, error = ?5, done = ?6, total = ?7 WHERE id = ?1",
+            params![
+                job.id,
+                job.entity.output.as_ref().map(|output| output.to_string_lossy()),
+                serde_json::to_string(&job.entity.without_credentials()).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?,
+                job.status.as_str(),
+                job.error,
+                job.progress.done,
+                job.progress.total,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// the newest finished download of `url`, the urls are stored without their credentials
+    pub fn find_download(&self, url: &str) -> rusqlite::Result<Option<HistoryEntry>> {
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            &format!("SELECT {} FROM downloads WHERE url = ?1 ORDER BY finished_at DESC, id DESC LIMIT 1", HISTORY_COLUMNS),
+            params![report::redact(url)],
+            history_entry,
+        ).optional()
+    }
+
+    /// records every file that was written, the parts of a split playlist each with their own size,
+    /// the urls without their credentials
+    pub fn record_download(&self, url: &str, downloaded: &Downloaded) -> Result<(), Box<dyn std::error::Error>> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+
+        for output in downloaded.outputs() {
+            let size = std::fs::metadata(output)?.len();
+
+            transaction.execute(
+                "INSERT INTO downloads (url, output, media_url, variant, size, finished_at) VALUES (?1, ?2, ?3, ?4, ?5, unixepoch())",
+                params![report::redact(url), output.to_string_lossy(), report::redact(downloaded.media_url.as_str()), downloaded.variant, size],
+            )?;
+        }
+
+        transaction.commit()?;
+
+        Ok(())
+    }
+
+    /// the newest `limit` finished downloads whose url contains `search`
+    pub fn history(&self, search: &str, limit: usize) -> rusqlite::Result<Vec<HistoryEntry>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&format!(
+            "SELECT {} FROM downloads WHERE instr(url, ?1) > 0 ORDER BY finished_at DESC, id DESC LIMIT ?2",
+            HISTORY_COLUMNS,
+        ))?;
+
+        let entries = statement.query_map(params![search, limit], history_entry)?;
+
+        entries.collect()
+    }
+}
+
+const HISTORY_COLUMNS: &str = "url, output, media_url, variant, size, datetime(finished_at, 'unixepoch')";
+
+fn history_entry(row: &rusqlite::Row) -> rusqlite::Result<HistoryEntry> {
+    Ok(HistoryEntry {
+        url: row.get(0)?,
+        output: PathBuf::from(row.get::<_, String>(1)?),
+        media_url: row.get(2)?,
+        variant: row.get(3)?,
+        size: row.get(4)?,
+        finished_at: row.get(5)?,
+    })
+}
+
+/// downloads `url` unless the history already has it and records it once it is finished
+pub async fn download(url: &str, output: Option<&Path>, options: &Options, client: &DownloadClient, cancel: &CancellationToken, store: &Store) -> Result<Downloaded, Box<dyn std::error::Error>> {
+    if !options.redownload {
+        match store.find_download(url) {
+            Ok(Some(entry)) => {
+                info!("{} was already downloaded to {} on {}", url, entry.output.to_string_lossy(), entry.finished_at);
+                return Err(Box::new(DuplicateError { output: entry.output }));
+            }
+            Ok(None) => {}
+            Err(err) => error!("Error reading download history: {}", err),
+        }
+    }
+
+    let downloaded = download::search::download(url, output, options, client, cancel).await?;
+
+    if let Err(err) = store.record_download(url, &downloaded) {
+        error!("Error recording download in history: {}", err);
+    }
+
+    Ok(downloaded)
+}
+
+pub fn print_history(entries: &[HistoryEntry]) {
+    let output_width = entries.iter()
+        .map(|entry| entry.output.to_string_lossy().len())
+        .chain(std::iter::once("OUTPUT".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!("{:19}  {:>10}  {:output_width$}  URL", "FINISHED", "SIZE", "OUTPUT");
+
+    for entry in entries {
+        print!("{:19}  {:>10}  {:output_width$}  {}", entry.finished_at, entry.size, entry.output.to_string_lossy(), report::redact(&entry.url));
+
+        if entry.media_url != entry.url {
+            print!(" ({})", report::redact(&entry.media_url));
+        }
+        if let Some(variant) = &entry.variant {
+            print!(" [{}]", variant);
+        }
+
+        println!();
+    }
+}